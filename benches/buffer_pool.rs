@@ -0,0 +1,78 @@
+#[macro_use]
+extern crate criterion;
+extern crate chat;
+
+use chat::buffer_pool::BufferPool;
+use chat::frame::WebSocketFrame;
+use criterion::{black_box, Criterion};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const FRAME_COUNT: usize = 100_000;
+
+// Counts allocations so the two read strategies can be compared directly.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn encoded_frames() -> Vec<u8> {
+    let mut wire = Vec::new();
+    for i in 0..FRAME_COUNT {
+        WebSocketFrame::binary(vec![i as u8; 32]).write(&mut wire).unwrap();
+    }
+    wire
+}
+
+fn read_unpooled(wire: &[u8]) {
+    let mut input = Cursor::new(wire);
+    for _ in 0..FRAME_COUNT {
+        black_box(WebSocketFrame::read(&mut input).unwrap());
+    }
+}
+
+fn read_pooled(wire: &[u8], pool: &mut BufferPool) {
+    let mut input = Cursor::new(wire);
+    for _ in 0..FRAME_COUNT {
+        let frame = WebSocketFrame::read_limited_into(&mut input, usize::MAX, pool.take()).unwrap();
+        pool.give(black_box(frame).payload);
+    }
+}
+
+fn count_allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn buffer_pool(c: &mut Criterion) {
+    let wire = encoded_frames();
+    let mut pool = BufferPool::new(1);
+
+    println!("allocations over {} frames: unpooled {}, pooled {}", FRAME_COUNT,
+             count_allocations(|| read_unpooled(&wire)),
+             count_allocations(|| read_pooled(&wire, &mut pool)));
+
+    let mut group = c.benchmark_group("read_100k_small_frames");
+    group.bench_function("unpooled", |b| b.iter(|| read_unpooled(&wire)));
+    group.bench_function("pooled", |b| b.iter(|| read_pooled(&wire, &mut pool)));
+    group.finish();
+}
+
+criterion_group!(benches, buffer_pool);
+criterion_main!(benches);
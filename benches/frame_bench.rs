@@ -0,0 +1,72 @@
+#[macro_use]
+extern crate criterion;
+extern crate chat;
+extern crate rand;
+
+use chat::frame::WebSocketFrame;
+use criterion::{black_box, BenchmarkId, Criterion, Throughput};
+
+use std::io::Cursor;
+
+const PAYLOAD_SIZES: [usize; 5] = [64, 1024, 16 * 1024, 64 * 1024, 1024 * 1024];
+const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+fn encoded(frame: &WebSocketFrame) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    frame.write(&mut bytes).unwrap();
+    bytes
+}
+
+fn random_payload(len: usize) -> Vec<u8> {
+    (0..len).map(|_| rand::random::<u8>()).collect()
+}
+
+fn read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_read");
+    for &size in PAYLOAD_SIZES.iter() {
+        let text = encoded(&WebSocketFrame::from(&*"a".repeat(size)));
+        let binary = encoded(&WebSocketFrame::binary(random_payload(size)));
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("text", size), &text, |b, bytes| {
+            b.iter(|| WebSocketFrame::read(&mut Cursor::new(black_box(&bytes[..]))).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("binary", size), &binary, |b, bytes| {
+            b.iter(|| WebSocketFrame::read(&mut Cursor::new(black_box(&bytes[..]))).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_write");
+    for &size in PAYLOAD_SIZES.iter() {
+        let frame = WebSocketFrame::binary(random_payload(size));
+        let mut output = Vec::with_capacity(size + 14);
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &frame, |b, frame| {
+            b.iter(|| {
+                output.clear();
+                black_box(frame).write(&mut output).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+fn apply_mask(c: &mut Criterion) {
+    let mut group = c.benchmark_group("apply_mask");
+    for &size in PAYLOAD_SIZES.iter() {
+        let mut payload = random_payload(size);
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| WebSocketFrame::apply_mask_in_place(black_box(MASK), black_box(&mut payload[..])))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, read, write, apply_mask);
+criterion_main!(benches);
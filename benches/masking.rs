@@ -0,0 +1,30 @@
+#[macro_use]
+extern crate criterion;
+extern crate chat;
+
+use chat::mask;
+use criterion::{black_box, Criterion, Throughput};
+
+const PAYLOAD_LEN: usize = 1024 * 1024;
+const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+fn masking(c: &mut Criterion) {
+    let mut payload: Vec<u8> = (0..PAYLOAD_LEN).map(|i| i as u8).collect();
+
+    let mut group = c.benchmark_group("apply_mask_1mb");
+    group.throughput(Throughput::Bytes(PAYLOAD_LEN as u64));
+
+    group.bench_function("scalar", |b| {
+        b.iter(|| mask::apply_mask_scalar(black_box(MASK), black_box(&mut payload[..])))
+    });
+
+    #[cfg(target_arch = "x86_64")]
+    group.bench_function("sse2", |b| {
+        b.iter(|| mask::apply_mask_sse2(black_box(MASK), black_box(&mut payload[..])))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, masking);
+criterion_main!(benches);
@@ -0,0 +1,97 @@
+#[macro_use]
+extern crate criterion;
+extern crate chat;
+
+use chat::frame::WebSocketFrame;
+use criterion::{BenchmarkId, Criterion, Throughput};
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+const SERVER_ADDR: &str = "127.0.0.1:10000";
+const CLIENT_COUNTS: [usize; 3] = [1, 10, 100];
+const FRAME_SIZES: [usize; 3] = [64, 1024, 64 * 1024];
+const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_server() -> Server {
+    let child = Command::new(env!("CARGO_BIN_EXE_chat"))
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start the chat server");
+    let server = Server(child);
+
+    for _ in 0..100 {
+        if TcpStream::connect(SERVER_ADDR).is_ok() {
+            return server;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("chat server did not start listening on {}", SERVER_ADDR);
+}
+
+fn connect() -> TcpStream {
+    let mut stream = TcpStream::connect(SERVER_ADDR).unwrap();
+    stream.set_nodelay(true).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\
+                       Host: localhost\r\n\
+                       Upgrade: websocket\r\n\
+                       Connection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n").unwrap();
+
+    // Read the 101 response byte by byte so no frame data is consumed with it.
+    let mut response = Vec::new();
+    let mut byte = [0; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+    assert!(response.starts_with(b"HTTP/1.1 101"));
+    stream
+}
+
+// Every client sends one text frame, then waits for the handler's reply.
+fn round_trip(clients: &mut [TcpStream], frame: &[u8]) {
+    for client in clients.iter_mut() {
+        client.write_all(frame).unwrap();
+    }
+    for client in clients.iter_mut() {
+        WebSocketFrame::read(client).unwrap();
+    }
+}
+
+fn throughput(c: &mut Criterion) {
+    let _server = start_server();
+
+    let mut group = c.benchmark_group("server_round_trip");
+    group.sample_size(20);
+    for &client_count in CLIENT_COUNTS.iter() {
+        let mut clients: Vec<TcpStream> = (0..client_count).map(|_| connect()).collect();
+
+        for &size in FRAME_SIZES.iter() {
+            let mut frame = Vec::new();
+            WebSocketFrame::from(&*"a".repeat(size)).write_masked(MASK, &mut frame).unwrap();
+
+            group.throughput(Throughput::Elements(client_count as u64));
+            let id = BenchmarkId::new(format!("{}_clients", client_count), size);
+            group.bench_function(id, |b| b.iter(|| round_trip(&mut clients, &frame)));
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
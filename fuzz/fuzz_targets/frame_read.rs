@@ -0,0 +1,20 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use chat::frame::{OpCode, WebSocketFrame};
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    let mut input = Cursor::new(data);
+
+    while let Ok(frame) = WebSocketFrame::read(&mut input) {
+        let mut output = Vec::new();
+        frame.write(&mut output).unwrap();
+
+        match frame.get_opcode() {
+            OpCode::Ping => { WebSocketFrame::pong(&frame); },
+            OpCode::ConnectionClose => { WebSocketFrame::close_from(&frame); },
+            _ => {}
+        }
+    }
+});
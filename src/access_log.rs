@@ -0,0 +1,95 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                                   "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// One line of the Apache Combined Log Format. The `Origin` header goes where
+// Apache puts the referer.
+pub struct AccessLogEntry<'a> {
+    pub ip: IpAddr,
+    pub at: SystemTime,
+    // Method and path; `None` if the request couldn't be parsed.
+    pub request: Option<(&'a str, &'a str)>,
+    pub status: u16,
+    pub bytes: usize,
+    pub origin: Option<&'a str>,
+    pub user_agent: Option<&'a str>
+}
+
+impl<'a> fmt::Display for AccessLogEntry<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} - - [{}] ", self.ip, timestamp(self.at))?;
+        match self.request {
+            Some((method, path)) => write!(f, "\"{} {} HTTP/1.1\"", method, path)?,
+            None => write!(f, "\"-\"")?
+        }
+        write!(f, " {} {} \"{}\" \"{}\"", self.status, self.bytes,
+               self.origin.unwrap_or("-"), self.user_agent.unwrap_or("-"))
+    }
+}
+
+// The status code and body length of an HTTP response we are about to send.
+pub fn response_summary(response: &[u8]) -> (u16, usize) {
+    let status = response.get(9..12)
+        .and_then(|code| std::str::from_utf8(code).ok())
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0);
+    let body_len = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => response.len() - end - 4,
+        None => 0
+    };
+    (status, body_len)
+}
+
+// `10/Oct/2000:13:55:36 +0000`, always in UTC.
+fn timestamp(at: SystemTime) -> String {
+    let secs = at.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000", day, MONTHS[month as usize - 1], year,
+            time / 3600, time / 60 % 60, time % 60)
+}
+
+// Days since 1970-01-01 to a (year, month, day) date, from Howard Hinnant's
+// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_a_combined_log_line() {
+        let entry = AccessLogEntry {
+            ip: "10.0.0.1".parse().unwrap(),
+            at: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            request: Some(("GET", "/chat")),
+            status: 101,
+            bytes: 0,
+            origin: Some("https://example.com"),
+            user_agent: None
+        };
+        assert_eq!(entry.to_string(),
+                   "10.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /chat HTTP/1.1\" 101 0 \"https://example.com\" \"-\"");
+    }
+
+    #[test]
+    fn summarizes_responses() {
+        assert_eq!(response_summary(b"HTTP/1.1 426 Upgrade Required\r\nContent-Length: 3\r\n\r\nabc"), (426, 3));
+        assert_eq!(response_summary(b"HTTP/1.1 400 Bad Request\r\n\r\n"), (400, 0));
+    }
+}
@@ -0,0 +1,32 @@
+// Buffers that grew past this are dropped rather than pooled, so one large
+// frame doesn't pin its allocation for the lifetime of the server.
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+pub struct BufferPool {
+    free: Vec<Vec<u8>>,
+    max_size: usize
+}
+
+impl BufferPool {
+    pub fn new(max_size: usize) -> BufferPool {
+        BufferPool {
+            free: Vec::with_capacity(max_size),
+            max_size: max_size
+        }
+    }
+
+    pub fn take(&mut self) -> Vec<u8> {
+        self.free.pop().unwrap_or_default()
+    }
+
+    pub fn give(&mut self, mut buf: Vec<u8>) {
+        if self.free.len() < self.max_size && buf.capacity() <= MAX_POOLED_CAPACITY {
+            buf.clear();
+            self.free.push(buf);
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        self.free.len()
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CbState {
+    Closed,
+    Open,
+    // The retry delay has passed; the next attempt decides whether the
+    // circuit closes again or stays open for another delay.
+    HalfOpen
+}
+
+// Stops outbound connection attempts for `retry_delay` after `threshold`
+// consecutive failures.
+pub struct CircuitBreaker {
+    state: CbState,
+    failure_count: u32,
+    threshold: u32,
+    retry_delay: Duration,
+    half_open_at: Option<Instant>
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, retry_delay: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            state: CbState::Closed,
+            failure_count: 0,
+            threshold: threshold,
+            retry_delay: retry_delay,
+            half_open_at: None
+        }
+    }
+
+    pub fn state(&self) -> CbState {
+        self.state
+    }
+
+    // Whether an attempt may be made now.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.state {
+            CbState::Closed | CbState::HalfOpen => true,
+            CbState::Open => {
+                if self.half_open_at.is_some_and(|at| now >= at) {
+                    self.state = CbState::HalfOpen;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.state = CbState::Closed;
+        self.failure_count = 0;
+        self.half_open_at = None;
+    }
+
+    pub fn record_failure(&mut self, now: Instant) {
+        self.failure_count = self.failure_count.saturating_add(1);
+        if self.state == CbState::HalfOpen || self.failure_count >= self.threshold {
+            self.state = CbState::Open;
+            self.half_open_at = Some(now + self.retry_delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold_failures_and_retries_later() {
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(5));
+        let now = Instant::now();
+
+        breaker.record_failure(now);
+        assert!(breaker.allow(now));
+        breaker.record_failure(now);
+        assert_eq!(breaker.state(), CbState::Open);
+        assert!(!breaker.allow(now + Duration::from_secs(4)));
+
+        assert!(breaker.allow(now + Duration::from_secs(5)));
+        assert_eq!(breaker.state(), CbState::HalfOpen);
+        breaker.record_success();
+        assert_eq!(breaker.state(), CbState::Closed);
+    }
+
+    #[test]
+    fn failure_while_half_open_reopens() {
+        let mut breaker = CircuitBreaker::new(3, Duration::from_secs(1));
+        let now = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure(now);
+        }
+
+        let later = now + Duration::from_secs(1);
+        assert!(breaker.allow(later));
+        breaker.record_failure(later);
+        assert_eq!(breaker.state(), CbState::Open);
+        assert!(!breaker.allow(later));
+    }
+}
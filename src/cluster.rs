@@ -0,0 +1,133 @@
+use crate::frame::{OpCode, WebSocketFrame};
+
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryFrom;
+
+// Subprotocol servers use for the connections between them.
+pub const PROTOCOL: &'static str = "chat-cluster";
+
+// A room broadcast forwarded to another server. On the wire it is the length
+// of the room name (u16, big-endian), the room name, the opcode of the original
+// frame and its payload. The frame carrying it also has a message id, see
+// `WebSocketFrame::with_message_id`.
+#[derive(Debug, PartialEq)]
+pub struct ClusterMessage {
+    pub room: String,
+    pub opcode: OpCode,
+    pub payload: Vec<u8>
+}
+
+impl ClusterMessage {
+    // Returns `None` if the room name is too long for its length prefix.
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        let room_len = u16::try_from(self.room.len()).ok()?;
+        let mut bytes = Vec::with_capacity(3 + self.room.len() + self.payload.len());
+        bytes.extend_from_slice(&room_len.to_be_bytes());
+        bytes.extend_from_slice(self.room.as_bytes());
+        bytes.push(self.opcode as u8);
+        bytes.extend_from_slice(&self.payload);
+        Some(bytes)
+    }
+
+    // Returns `None` for anything that isn't a well-formed text or binary message.
+    pub fn decode(bytes: &[u8]) -> Option<ClusterMessage> {
+        if bytes.len() < 2 {
+            return None;
+        }
+        let room_len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+
+        let rest = &bytes[2..];
+        if rest.len() < room_len + 1 {
+            return None;
+        }
+        let room = String::from_utf8(rest[..room_len].to_vec()).ok()?;
+        let opcode = match rest[room_len] {
+            1 => OpCode::TextFrame,
+            2 => OpCode::BinaryFrame,
+            _ => return None
+        };
+
+        Some(ClusterMessage {
+            room: room,
+            opcode: opcode,
+            payload: rest[room_len + 1..].to_vec()
+        })
+    }
+
+    pub fn into_frame(self) -> Option<WebSocketFrame> {
+        match self.opcode {
+            OpCode::TextFrame => String::from_utf8(self.payload).ok().map(|text| WebSocketFrame::from(&*text)),
+            _ => Some(WebSocketFrame::binary(self.payload))
+        }
+    }
+}
+
+// Ids of recently seen cluster messages, so a message reaching a server twice
+// is only delivered once. The oldest ids are forgotten first.
+pub struct SeenMessages {
+    ids: HashSet<u128>,
+    order: VecDeque<u128>,
+    capacity: usize
+}
+
+impl SeenMessages {
+    pub fn new(capacity: usize) -> SeenMessages {
+        SeenMessages {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            capacity: capacity
+        }
+    }
+
+    // Returns false if the id was already seen.
+    pub fn insert(&mut self, id: u128) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_round_trip() {
+        let message = ClusterMessage { room: "lobby".to_string(), opcode: OpCode::TextFrame,
+                                       payload: b"hello".to_vec() };
+        let encoded = message.encode().unwrap();
+        let decoded = ClusterMessage::decode(&encoded).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.into_frame().unwrap().payload, b"hello");
+
+        assert!(ClusterMessage::decode(&encoded[..4]).is_none());
+    }
+
+    #[test]
+    fn room_names_must_fit_the_length_prefix() {
+        let mut message = ClusterMessage { room: "a".repeat(u16::MAX as usize), opcode: OpCode::BinaryFrame,
+                                           payload: Vec::new() };
+        assert!(message.encode().is_some());
+
+        message.room.push('a');
+        assert!(message.encode().is_none());
+    }
+
+    #[test]
+    fn seen_messages_forget_the_oldest() {
+        let mut seen = SeenMessages::new(2);
+        assert!(seen.insert(1));
+        assert!(!seen.insert(1));
+        assert!(seen.insert(2));
+        assert!(seen.insert(3));
+        assert!(seen.insert(1));
+        assert!(!seen.insert(3));
+    }
+}
@@ -0,0 +1,81 @@
+use crate::deflate::CompressionConfig;
+
+use std::time::Duration;
+
+pub const DEFAULT_MAX_PAYLOAD_LEN: usize = 16 * 1024 * 1024;
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+pub const DEFAULT_LISTEN_BACKLOG: i32 = 128;
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 8192;
+pub const DEFAULT_HANDSHAKE_TIMEOUT_MS: u64 = 10_000;
+
+// Runtime parameters of a `WebSocketServer`, set through its `with_*`
+// builders or swapped wholesale with `reload_config`. Every client holds a
+// reference to the current configuration and enforces its limits from there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub backlog: i32,
+    pub nodelay: bool,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub proxy_protocol: bool,
+    pub trust_forwarded_headers: bool,
+    pub handshake_timeout_ms: u64,
+    pub max_handshake_retries: u8,
+    pub max_header_size: usize,
+    pub read_timeout: Option<Duration>,
+    // Connections that haven't sent anything for `idle_timeout_ms` are closed.
+    // With `ping_interval_ms` they are pinged first, and closed if no pong
+    // arrives within `pong_timeout_ms`.
+    pub idle_timeout_ms: Option<u64>,
+    pub ping_interval_ms: Option<u64>,
+    pub pong_timeout_ms: Option<u64>,
+    pub max_payload_len: usize,
+    pub max_message_size: usize,
+    pub max_frame_size: Option<usize>,
+    // Tokens per second and burst size of each client's incoming frames.
+    pub rate_limit: Option<(f64, u32)>,
+    pub send_deadline: Option<Duration>,
+    pub max_dropped_frames: usize,
+    pub max_outgoing_queue: Option<usize>,
+    pub message_ttl: Option<Duration>,
+    pub coalesce_window_ms: u64,
+    pub session_ttl_ms: Option<u64>,
+    pub compression: CompressionConfig,
+    // Sequence numbers aren't negotiated, so only enable them when the
+    // clients are known to expect them.
+    #[cfg(feature = "sequence")]
+    pub sequence_numbers: bool
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            backlog: DEFAULT_LISTEN_BACKLOG,
+            nodelay: false,
+            max_connections: None,
+            max_connections_per_ip: None,
+            proxy_protocol: false,
+            trust_forwarded_headers: false,
+            handshake_timeout_ms: DEFAULT_HANDSHAKE_TIMEOUT_MS,
+            max_handshake_retries: 0,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            read_timeout: None,
+            idle_timeout_ms: None,
+            ping_interval_ms: None,
+            pong_timeout_ms: None,
+            max_payload_len: DEFAULT_MAX_PAYLOAD_LEN,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_frame_size: None,
+            rate_limit: None,
+            send_deadline: None,
+            max_dropped_frames: 0,
+            max_outgoing_queue: None,
+            message_ttl: None,
+            coalesce_window_ms: 0,
+            session_ttl_ms: None,
+            compression: CompressionConfig::default(),
+            #[cfg(feature = "sequence")]
+            sequence_numbers: false
+        }
+    }
+}
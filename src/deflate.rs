@@ -0,0 +1,146 @@
+pub const MIN_WINDOW_BITS: u8 = 9;
+pub const MAX_WINDOW_BITS: u8 = 15;
+
+// Server-side settings for `permessage-deflate`. Only the negotiation is
+// implemented: the offer is checked and the parameters agreed, but nothing is
+// compressed and the extension isn't accepted in the handshake response. The
+// `no_context_takeover` flags and `max_window_bits` shape what gets agreed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    pub client_no_context_takeover: bool,
+    pub server_no_context_takeover: bool,
+    pub max_window_bits: u8
+}
+
+impl Default for CompressionConfig {
+    fn default() -> CompressionConfig {
+        CompressionConfig {
+            enabled: false,
+            client_no_context_takeover: false,
+            server_no_context_takeover: false,
+            max_window_bits: MAX_WINDOW_BITS
+        }
+    }
+}
+
+// The parameters agreed for `permessage-deflate` (RFC 7692, section 7.1).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeflateParams {
+    pub client_max_window_bits: u8,
+    pub server_max_window_bits: u8,
+    pub client_no_context_takeover: bool,
+    pub server_no_context_takeover: bool
+}
+
+impl Default for DeflateParams {
+    fn default() -> DeflateParams {
+        DeflateParams {
+            client_max_window_bits: MAX_WINDOW_BITS,
+            server_max_window_bits: MAX_WINDOW_BITS,
+            client_no_context_takeover: false,
+            server_no_context_takeover: false
+        }
+    }
+}
+
+// Picks the first `permessage-deflate` offer out of a `Sec-WebSocket-Extensions`
+// header. Returns `Ok(None)` if the client didn't offer it or compression is
+// disabled, and an error for window bits outside 9-15.
+pub fn negotiate(extensions: &str, config: &CompressionConfig) -> Result<Option<DeflateParams>, String> {
+    if !config.enabled {
+        return Ok(None);
+    }
+
+    for offer in extensions.split(',') {
+        let mut params = offer.split(';').map(|param| param.trim());
+        if params.next() != Some("permessage-deflate") {
+            continue;
+        }
+
+        // The client's window can only be limited if it offers `client_max_window_bits`.
+        let mut agreed = DeflateParams {
+            client_max_window_bits: MAX_WINDOW_BITS,
+            server_max_window_bits: config.max_window_bits,
+            client_no_context_takeover: config.client_no_context_takeover,
+            server_no_context_takeover: config.server_no_context_takeover
+        };
+        for param in params {
+            let (name, value) = match param.find('=') {
+                Some(eq) => (param[..eq].trim(), Some(param[eq + 1..].trim().trim_matches('"'))),
+                None => (param, None)
+            };
+
+            match (name, value) {
+                // Without a value the client only says it can handle a smaller window.
+                ("client_max_window_bits", None) => agreed.client_max_window_bits = config.max_window_bits,
+                ("client_max_window_bits", Some(bits)) => {
+                    agreed.client_max_window_bits = config.max_window_bits.min(window_bits(name, bits)?);
+                },
+                ("server_max_window_bits", Some(bits)) => {
+                    agreed.server_max_window_bits = agreed.server_max_window_bits.min(window_bits(name, bits)?);
+                },
+                ("server_max_window_bits", None) => return Err("server_max_window_bits needs a value".to_string()),
+                ("client_no_context_takeover", None) => agreed.client_no_context_takeover = true,
+                ("server_no_context_takeover", None) => agreed.server_no_context_takeover = true,
+                _ => {}
+            }
+        }
+        return Ok(Some(agreed));
+    }
+    Ok(None)
+}
+
+fn window_bits(name: &str, value: &str) -> Result<u8, String> {
+    match value.parse() {
+        Ok(bits) if (MIN_WINDOW_BITS..=MAX_WINDOW_BITS).contains(&bits) => Ok(bits),
+        _ => Err(format!("{} must be between {} and {}, got {}", name, MIN_WINDOW_BITS, MAX_WINDOW_BITS, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled() -> CompressionConfig {
+        CompressionConfig { enabled: true, ..CompressionConfig::default() }
+    }
+
+    #[test]
+    fn defaults_to_the_largest_window() {
+        assert_eq!(negotiate("permessage-deflate; client_max_window_bits", &enabled()),
+                   Ok(Some(DeflateParams::default())));
+        assert_eq!(negotiate("x-webkit-deflate-frame", &enabled()), Ok(None));
+    }
+
+    #[test]
+    fn window_bits_are_taken_from_the_first_offer() {
+        let params = negotiate("x-foo, permessage-deflate; server_max_window_bits=10; client_max_window_bits=\"12\", \
+                                permessage-deflate", &enabled()).unwrap().unwrap();
+        assert_eq!(params.server_max_window_bits, 10);
+        assert_eq!(params.client_max_window_bits, 12);
+    }
+
+    #[test]
+    fn config_caps_the_window_and_sets_context_takeover() {
+        let config = CompressionConfig { max_window_bits: 11, server_no_context_takeover: true, ..enabled() };
+        let params = negotiate("permessage-deflate; server_max_window_bits=13; client_max_window_bits; \
+                                client_no_context_takeover", &config).unwrap().unwrap();
+        assert_eq!(params.server_max_window_bits, 11);
+        assert_eq!(params.client_max_window_bits, 11);
+        assert!(params.client_no_context_takeover);
+        assert!(params.server_no_context_takeover);
+
+        let params = negotiate("permessage-deflate", &config).unwrap().unwrap();
+        assert_eq!(params.client_max_window_bits, MAX_WINDOW_BITS);
+
+        assert_eq!(negotiate("permessage-deflate", &CompressionConfig::default()), Ok(None));
+    }
+
+    #[test]
+    fn out_of_range_window_bits_are_rejected() {
+        assert!(negotiate("permessage-deflate; server_max_window_bits=8", &enabled()).is_err());
+        assert!(negotiate("permessage-deflate; client_max_window_bits=16", &enabled()).is_err());
+        assert!(negotiate("permessage-deflate; server_max_window_bits", &enabled()).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    Io(io::Error),
+    InvalidOpcode(u8),
+    InvalidUtf8(FromUtf8Error),
+    PayloadTooLarge { limit: usize, actual: usize },
+    ClientNotFound,
+    InvalidProxyHeader(String),
+    InvalidHandshake(String),
+    InvalidRoom(String),
+    // RSV1 set on a frame; there is no permessage-deflate codec to undo it.
+    CompressedFrame,
+    CircuitOpen
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WebSocketError::Io(ref e) => write!(f, "IO error: {}", e),
+            WebSocketError::InvalidOpcode(op) => write!(f, "Invalid opcode: {}", op),
+            WebSocketError::InvalidUtf8(ref e) => write!(f, "Invalid UTF-8 in text frame: {}", e),
+            WebSocketError::PayloadTooLarge { limit, actual } =>
+                write!(f, "Payload of {} bytes exceeds the limit of {} bytes", actual, limit),
+            WebSocketError::ClientNotFound => write!(f, "Client not found"),
+            WebSocketError::InvalidProxyHeader(ref reason) => write!(f, "Invalid PROXY header: {}", reason),
+            WebSocketError::InvalidHandshake(ref reason) => write!(f, "Invalid handshake: {}", reason),
+            WebSocketError::InvalidRoom(ref reason) => write!(f, "Invalid room: {}", reason),
+            WebSocketError::CompressedFrame => write!(f, "Compressed frames are not supported"),
+            WebSocketError::CircuitOpen => write!(f, "Circuit open: not connecting upstream")
+        }
+    }
+}
+
+impl Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            WebSocketError::Io(ref e) => Some(e),
+            WebSocketError::InvalidUtf8(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(e: io::Error) -> WebSocketError {
+        WebSocketError::Io(e)
+    }
+}
@@ -0,0 +1,32 @@
+use crate::frame::CloseCode;
+
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+const MAX_EVENTS: usize = 100;
+
+#[derive(Debug, Clone)]
+pub enum ConnectionEventKind {
+    Opened,
+    HandshakeFailed(String),
+    Closed(CloseCode),
+    IoError(String),
+    MessageSent,
+    MessageReceived
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionEvent {
+    pub at: SystemTime,
+    pub kind: ConnectionEventKind
+}
+
+pub fn record(events: &mut VecDeque<ConnectionEvent>, kind: ConnectionEventKind) {
+    if events.len() == MAX_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(ConnectionEvent {
+        at: SystemTime::now(),
+        kind: kind
+    });
+}
@@ -0,0 +1,74 @@
+use crate::error::WebSocketError;
+use crate::frame::WebSocketFrame;
+#[cfg(feature = "sequence")]
+use crate::sequence::SequenceExtension;
+
+// Per-connection state of the extensions applied to incoming frames once
+// they have been parsed. Keep one per connection. There is no deflate member:
+// until a codec exists, frames with RSV1 set are rejected rather than handed
+// on still compressed.
+#[derive(Default)]
+pub struct ExtensionContext {
+    #[cfg(feature = "sequence")]
+    pub sequence: Option<SequenceExtension>
+}
+
+impl ExtensionContext {
+    // Every extension compiled in is enabled.
+    pub fn new() -> ExtensionContext {
+        ExtensionContext {
+            #[cfg(feature = "sequence")]
+            sequence: Some(SequenceExtension::new())
+        }
+    }
+
+    pub fn decode(&mut self, frame: WebSocketFrame) -> Result<WebSocketFrame, WebSocketError> {
+        if frame.rsv1() {
+            return Err(WebSocketError::CompressedFrame);
+        }
+        #[cfg(feature = "sequence")]
+        if let Some(ref mut sequence) = self.sequence {
+            return Ok(sequence.verify(frame));
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_frames_pass_through() {
+        let mut bytes = Vec::new();
+        WebSocketFrame::from("hello").write(&mut bytes).unwrap();
+
+        let mut context = ExtensionContext::new();
+        let frame = WebSocketFrame::read_with_context(&mut &bytes[..], &mut context).unwrap();
+        assert_eq!(frame.payload, b"hello");
+    }
+
+    #[test]
+    fn compressed_frames_are_rejected() {
+        let mut frame = WebSocketFrame::from("hello");
+        frame.set_rsv1(true);
+        let mut bytes = Vec::new();
+        frame.write(&mut bytes).unwrap();
+
+        match WebSocketFrame::read_with_context(&mut &bytes[..], &mut ExtensionContext::new()) {
+            Err(WebSocketError::CompressedFrame) => {},
+            other => panic!("expected CompressedFrame, got {:?}", other.map(|frame| frame.payload))
+        }
+    }
+
+    #[cfg(feature = "sequence")]
+    #[test]
+    fn sequence_numbers_are_stripped() {
+        let mut bytes = Vec::new();
+        SequenceExtension::new().stamp(WebSocketFrame::from("hello")).write(&mut bytes).unwrap();
+
+        let frame = WebSocketFrame::read_with_context(&mut &bytes[..], &mut ExtensionContext::new()).unwrap();
+        assert_eq!(frame.payload, b"hello");
+        assert!(!frame.rsv2());
+    }
+}
@@ -0,0 +1,33 @@
+use crate::frame::WebSocketFrame;
+
+pub enum FilterResult {
+    Pass,
+    Drop,
+    Replace(WebSocketFrame)
+}
+
+pub trait FrameFilter: Send + 'static {
+    fn filter_incoming(&self, frame: &WebSocketFrame) -> FilterResult;
+    fn filter_outgoing(&self, frame: &WebSocketFrame) -> FilterResult;
+}
+
+pub fn apply_incoming(filters: &[Box<dyn FrameFilter>], frame: WebSocketFrame) -> Option<WebSocketFrame> {
+    apply(filters, frame, |filter, frame| filter.filter_incoming(frame))
+}
+
+pub fn apply_outgoing(filters: &[Box<dyn FrameFilter>], frame: WebSocketFrame) -> Option<WebSocketFrame> {
+    apply(filters, frame, |filter, frame| filter.filter_outgoing(frame))
+}
+
+fn apply<F>(filters: &[Box<dyn FrameFilter>], mut frame: WebSocketFrame, f: F) -> Option<WebSocketFrame>
+    where F: Fn(&dyn FrameFilter, &WebSocketFrame) -> FilterResult
+{
+    for filter in filters.iter() {
+        match f(&**filter, &frame) {
+            FilterResult::Pass => {},
+            FilterResult::Drop => return None,
+            FilterResult::Replace(replacement) => frame = replacement
+        }
+    }
+    Some(frame)
+}
@@ -1,16 +1,27 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::iter;
+#[cfg(feature = "bytes")]
+use bytes::Bytes;
+use crate::error::WebSocketError;
+use crate::extension::ExtensionContext;
+use crate::mask;
+use std::fmt;
 use std::io;
 use std::io::Result as IOResult;
 use std::io::{Read, Write, Error};
-use std::u16;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncWrite};
 
 const PAYLOAD_LEN_U16: u8 = 126;
 const PAYLOAD_LEN_U64: u8 = 127;
+#[cfg(feature = "cluster")]
+const MESSAGE_ID_FLAG: [u8; 2] = [0xC1, 0x1D];
+#[cfg(feature = "cluster")]
+const MESSAGE_ID_LEN: usize = 16;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[allow(dead_code)]
 pub enum OpCode {
+    Continuation = 0,
     TextFrame = 1,
     BinaryFrame = 2,
     ConnectionClose = 8,
@@ -21,6 +32,7 @@ pub enum OpCode {
 impl OpCode {
     fn from(op: u8) -> Option<OpCode> {
         match op {
+            0 => Some(OpCode::Continuation),
             1 => Some(OpCode::TextFrame),
             2 => Some(OpCode::BinaryFrame),
             8 => Some(OpCode::ConnectionClose),
@@ -31,7 +43,81 @@ impl OpCode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum CloseCode {
+    Normal,
+    GoingAway,
+    ProtocolError,
+    Unsupported,
+    InvalidPayload,
+    PolicyViolation,
+    MessageTooBig,
+    InternalError,
+    // Any other code, e.g. an application-defined one in 4000-4999.
+    Custom(u16)
+}
+
+impl CloseCode {
+    // Only the codes with a variant of their own; see `From<u16>` for the rest.
+    pub fn from_u16(code: u16) -> Option<CloseCode> {
+        match code {
+            1000 => Some(CloseCode::Normal),
+            1001 => Some(CloseCode::GoingAway),
+            1002 => Some(CloseCode::ProtocolError),
+            1003 => Some(CloseCode::Unsupported),
+            1007 => Some(CloseCode::InvalidPayload),
+            1008 => Some(CloseCode::PolicyViolation),
+            1009 => Some(CloseCode::MessageTooBig),
+            1011 => Some(CloseCode::InternalError),
+            _ => None
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match *self {
+            CloseCode::Normal => "Normal Closure",
+            CloseCode::GoingAway => "Going Away",
+            CloseCode::ProtocolError => "Protocol Error",
+            CloseCode::Unsupported => "Unsupported Data",
+            CloseCode::InvalidPayload => "Invalid Frame Payload Data",
+            CloseCode::PolicyViolation => "Policy Violation",
+            CloseCode::MessageTooBig => "Message Too Big",
+            CloseCode::InternalError => "Internal Error",
+            CloseCode::Custom(_) => "Custom"
+        }
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> CloseCode {
+        CloseCode::from_u16(code).unwrap_or(CloseCode::Custom(code))
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::Unsupported => 1003,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::InternalError => 1011,
+            CloseCode::Custom(code) => code
+        }
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", u16::from(*self), self.description())
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct WebSocketFrameHeader {
     fin: bool,
     rsv1: bool,
@@ -66,7 +152,7 @@ impl WebSocketFrameHeader {
     }
 }
 
-impl<'a> From<&'a str> for WebSocketFrame {
+impl From<&str> for WebSocketFrame {
     fn from(payload: &str) -> WebSocketFrame {
         WebSocketFrame {
             header: WebSocketFrameHeader::new_header(payload.len(), OpCode::TextFrame),
@@ -76,7 +162,15 @@ impl<'a> From<&'a str> for WebSocketFrame {
     }
 }
 
-#[derive(Debug)]
+// A binary frame carrying `payload`.
+#[cfg(feature = "bytes")]
+impl From<Bytes> for WebSocketFrame {
+    fn from(payload: Bytes) -> WebSocketFrame {
+        WebSocketFrame::from_bytes_payload(OpCode::BinaryFrame, payload, true)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct WebSocketFrame {
     header: WebSocketFrameHeader,
     mask: Option<[u8; 4]>,
@@ -84,21 +178,88 @@ pub struct WebSocketFrame {
 }
 
 impl WebSocketFrame {
-    pub fn read<R: Read>(input: &mut R) -> IOResult<WebSocketFrame> {
-        let buf = try!(input.read_u16::<BigEndian>());
-        let header = Self::parse_header(buf).unwrap();
+    pub fn read<R: Read>(input: &mut R) -> Result<WebSocketFrame, WebSocketError> {
+        Self::read_limited(input, usize::MAX)
+    }
+
+    pub fn read_limited<R: Read>(input: &mut R, max_payload_len: usize) -> Result<WebSocketFrame, WebSocketError> {
+        Self::read_limited_into(input, max_payload_len, Vec::new())
+    }
+
+    // Reads the payload into `payload_buf`, reusing its allocation.
+    pub fn read_limited_into<R: Read>(input: &mut R, max_payload_len: usize,
+                                      payload_buf: Vec<u8>) -> Result<WebSocketFrame, WebSocketError> {
+        let buf = input.read_u16::<BigEndian>().map_err(io::Error::from)?;
+        let header = Self::parse_header(buf)?;
+
+        let len = Self::read_length(header.payload_length, input)?;
+        if len > max_payload_len {
+            return Err(WebSocketError::PayloadTooLarge { limit: max_payload_len, actual: len });
+        }
 
-        let len = try!(Self::read_length(header.payload_length, input));
         let mask_key = if header.masked {
-            let mask = try!(Self::read_mask(input));
+            let mask = Self::read_mask(input)?;
             Some(mask)
         } else {
             None
         };
-        let mut payload = try!(Self::read_payload(len, input));
+        let mut payload = Self::read_payload(len, input, payload_buf)?;
 
         if let Some(mask) = mask_key {
-            Self::apply_mask(mask, &mut payload);
+            Self::apply_mask_in_place(mask, &mut payload[..]);
+        }
+
+        Ok(WebSocketFrame {
+            header: header,
+            payload: payload,
+            mask: mask_key
+        })
+    }
+
+    // Reads a frame and runs it through the connection's extensions.
+    pub fn read_with_context<R: Read>(input: &mut R, context: &mut ExtensionContext) -> Result<WebSocketFrame, WebSocketError> {
+        context.decode(Self::read(input)?)
+    }
+
+    #[cfg(feature = "tokio")]
+    pub async fn read_async<R: AsyncRead + Unpin>(input: &mut R) -> Result<WebSocketFrame, WebSocketError> {
+        Self::read_limited_async(input, usize::MAX).await
+    }
+
+    #[cfg(feature = "tokio")]
+    pub async fn read_limited_async<R: AsyncRead + Unpin>(input: &mut R,
+                                                          max_payload_len: usize) -> Result<WebSocketFrame, WebSocketError> {
+        // Scoped here so the tokio extension methods don't clash with byteorder's.
+        use tokio::io::AsyncReadExt;
+
+        let header = Self::parse_header(input.read_u16().await?)?;
+
+        let len = match header.payload_length {
+            PAYLOAD_LEN_U64 => input.read_u64().await? as usize,
+            PAYLOAD_LEN_U16 => input.read_u16().await? as usize,
+            payload_len => payload_len as usize
+        };
+        if len > max_payload_len {
+            return Err(WebSocketError::PayloadTooLarge { limit: max_payload_len, actual: len });
+        }
+
+        let mask_key = if header.masked {
+            let mut mask = [0; 4];
+            input.read_exact(&mut mask).await?;
+            Some(mask)
+        } else {
+            None
+        };
+
+        // Same as `read_payload`: don't trust the length for an allocation.
+        let mut payload = Vec::new();
+        input.take(len as u64).read_to_end(&mut payload).await?;
+        if payload.len() < len {
+            return Err(WebSocketError::from(Error::new(io::ErrorKind::UnexpectedEof, "frame payload truncated")));
+        }
+
+        if let Some(mask) = mask_key {
+            Self::apply_mask_in_place(mask, &mut payload[..]);
         }
 
         Ok(WebSocketFrame {
@@ -109,10 +270,68 @@ impl WebSocketFrame {
     }
 
     pub fn get_opcode(&self) -> OpCode {
-        self.header.opcode.clone()
+        self.header.opcode
+    }
+
+    pub fn fin(&self) -> bool {
+        self.header.fin
     }
 
-    fn parse_header(buf: u16) -> Result<WebSocketFrameHeader, String> {
+    pub fn is_masked(&self) -> bool {
+        self.header.masked
+    }
+
+    // The key the payload was unmasked with, for frames read off the wire.
+    pub fn mask(&self) -> Option<[u8; 4]> {
+        self.mask
+    }
+
+    pub fn rsv1(&self) -> bool {
+        self.header.rsv1
+    }
+
+    pub fn rsv2(&self) -> bool {
+        self.header.rsv2
+    }
+
+    pub fn rsv3(&self) -> bool {
+        self.header.rsv3
+    }
+
+    // The 7-bit length field as it appeared in the header: the payload length
+    // itself, or 126/127 when an extended length follows.
+    pub fn header_payload_length(&self) -> u8 {
+        self.header.payload_length
+    }
+
+    pub fn set_rsv1(&mut self, v: bool) {
+        self.header.rsv1 = v;
+    }
+
+    pub fn set_rsv2(&mut self, v: bool) {
+        self.header.rsv2 = v;
+    }
+
+    pub fn set_rsv3(&mut self, v: bool) {
+        self.header.rsv3 = v;
+    }
+
+    // Header and payload length of the frame at the start of `buf`, or `None`
+    // until enough of the header has arrived to tell.
+    pub fn peek_len(buf: &[u8]) -> Option<(usize, usize)> {
+        if buf.len() < 2 {
+            return None;
+        }
+        let mask_len = if buf[1] & 0x80 == 0x80 { 4 } else { 0 };
+        let (length_len, payload_len) = match buf[1] & 0x7F {
+            PAYLOAD_LEN_U16 => (2, (&buf[2..]).read_u16::<BigEndian>().ok()? as usize),
+            PAYLOAD_LEN_U64 => (8, (&buf[2..]).read_u64::<BigEndian>().ok()? as usize),
+            len => (0, len as usize)
+        };
+        Some((2 + length_len + mask_len, payload_len))
+    }
+
+    fn parse_header(buf: u16) -> Result<WebSocketFrameHeader, WebSocketError> {
         let opcode_num = ((buf >> 8) as u8) & 0x0F;
         let opcode = OpCode::from(opcode_num);
 
@@ -127,38 +346,41 @@ impl WebSocketFrame {
                 payload_length: (buf as u8) & 0x7F,
             })
         } else {
-            Err(format!("Invalid opcode: {}", opcode_num))
+            Err(WebSocketError::InvalidOpcode(opcode_num))
         }
     }
 
-    fn apply_mask(mask: [u8; 4], bytes: &mut Vec<u8>) {
-        for (i, c) in bytes.iter_mut().enumerate() {
-            *c = *c ^ mask[i % 4];
-        }
+    pub fn apply_mask_in_place(mask: [u8; 4], bytes: &mut [u8]) {
+        mask::apply_mask(mask, bytes);
     }
 
     fn read_mask<R: Read>(input: &mut R) -> IOResult<[u8; 4]> {
         let mut buf = [0; 4];
-        try!(input.read(&mut buf));
+        input.read_exact(&mut buf)?;
         Ok(buf)
     }
 
-    fn read_payload<R: Read>(payload_len: usize, input: &mut R) -> IOResult<Vec<u8>> {
-        let mut payload: Vec<u8> = Vec::with_capacity(payload_len);
-        payload.extend(iter::repeat(0).take(payload_len));
-        try!(input.read(&mut payload));
+    fn read_payload<R: Read>(payload_len: usize, input: &mut R, mut payload: Vec<u8>) -> IOResult<Vec<u8>> {
+        // The length comes straight off the wire, so let the buffer grow with
+        // the bytes actually received instead of trusting it for an allocation.
+        payload.clear();
+        input.take(payload_len as u64).read_to_end(&mut payload)?;
+
+        if payload.len() < payload_len {
+            return Err(Error::new(io::ErrorKind::UnexpectedEof, "frame payload truncated"));
+        }
         Ok(payload)
     }
 
     fn read_length<R: Read>(payload_len: u8, input: &mut R) -> IOResult<usize> {
-        return match payload_len {
-            PAYLOAD_LEN_U64 => input.read_u64::<BigEndian>().map(|v| v as usize).map_err(|e| io::Error::from(e)),
-            PAYLOAD_LEN_U16 => input.read_u16::<BigEndian>().map(|v| v as usize).map_err(|e| io::Error::from(e)),
+        match payload_len {
+            PAYLOAD_LEN_U64 => input.read_u64::<BigEndian>().map(|v| v as usize).map_err(io::Error::from),
+            PAYLOAD_LEN_U16 => input.read_u16::<BigEndian>().map(|v| v as usize).map_err(io::Error::from),
             _ => Ok(payload_len as usize)
         }
     }
 
-    fn serialize_header(header: &WebSocketFrameHeader) -> u16 {
+    fn serialize_header(header: &WebSocketFrameHeader, payload_length: u8) -> u16 {
         let b1 = ((header.fin as u8) << 7)
                   | ((header.rsv1 as u8) << 6)
                   | ((header.rsv2 as u8) << 5)
@@ -166,25 +388,150 @@ impl WebSocketFrame {
                   | ((header.opcode as u8) & 0x7F);
 
         let b2 = ((header.masked as u8) << 7)
-                  | ((header.payload_length as u8) & 0x7F);
+                  | (payload_length & 0x7F);
 
         ((b1 as u16) << 8) | (b2 as u16)
     }
 
-    pub fn write<W: Write>(&self, output: &mut W) -> IOResult<()> {
-        let hdr = Self::serialize_header(&self.header);
-        try!(output.write_u16::<BigEndian>(hdr));
+    fn write_header<W: Write>(header: &WebSocketFrameHeader, payload_len: usize, output: &mut W) -> IOResult<()> {
+        // `payload` is public and may have changed size since the header was
+        // built, so derive the length encoding from what is actually sent.
+        let payload_length = WebSocketFrameHeader::determine_len(payload_len);
+        let hdr = Self::serialize_header(header, payload_length);
+        output.write_u16::<BigEndian>(hdr)?;
 
-        match self.header.payload_length {
-            PAYLOAD_LEN_U16 => try!(output.write_u16::<BigEndian>(self.payload.len() as u16)),
-            PAYLOAD_LEN_U64 => try!(output.write_u64::<BigEndian>(self.payload.len() as u64)),
+        match payload_length {
+            PAYLOAD_LEN_U16 => output.write_u16::<BigEndian>(payload_len as u16)?,
+            PAYLOAD_LEN_U64 => output.write_u64::<BigEndian>(payload_len as u64)?,
             _ => {}
         }
+        Ok(())
+    }
+
+    pub fn write<W: Write>(&self, output: &mut W) -> IOResult<()> {
+        Self::write_header(&self.header, self.payload.len(), output)?;
+        output.write_all(&self.payload)?;
+        Ok(())
+    }
+
+    // Serializes into memory first so the frame goes out in a single write.
+    #[cfg(feature = "tokio")]
+    pub async fn write_async<W: AsyncWrite + Unpin>(&self, output: &mut W) -> IOResult<()> {
+        use tokio::io::AsyncWriteExt;
 
-        try!(output.write(&self.payload));
+        let mut bytes = Vec::with_capacity(self.payload.len() + 14);
+        self.write(&mut bytes)?;
+        output.write_all(&bytes).await
+    }
+
+    // Clients must mask every frame they send (RFC 6455, section 5.3).
+    pub fn write_masked<W: Write>(&self, mask: [u8; 4], output: &mut W) -> IOResult<()> {
+        let mut header = self.header.clone();
+        header.masked = true;
+        Self::write_header(&header, self.payload.len(), output)?;
+        output.write_all(&mask)?;
+
+        let mut payload = self.payload.clone();
+        Self::apply_mask_in_place(mask, &mut payload[..]);
+        output.write_all(&payload)?;
         Ok(())
     }
 
+    pub fn fragment(payload: &[u8], fragment_size: usize, opcode: OpCode) -> Vec<WebSocketFrame> {
+        assert!(fragment_size > 0, "fragment_size must be non-zero");
+
+        if payload.len() <= fragment_size {
+            return vec![Self::with_fin(opcode, payload.to_vec(), true)];
+        }
+
+        let count = payload.len().div_ceil(fragment_size);
+        payload.chunks(fragment_size).enumerate().map(|(i, chunk)| {
+            let opcode = if i == 0 { opcode } else { OpCode::Continuation };
+            Self::with_fin(opcode, chunk.to_vec(), i == count - 1)
+        }).collect()
+    }
+
+    fn with_fin(opcode: OpCode, payload: Vec<u8>, fin: bool) -> WebSocketFrame {
+        let mut header = WebSocketFrameHeader::new_header(payload.len(), opcode);
+        header.fin = fin;
+
+        WebSocketFrame {
+            header: header,
+            payload: payload,
+            mask: None
+        }
+    }
+
+    pub fn is_data(&self) -> bool {
+        matches!(self.header.opcode, OpCode::TextFrame | OpCode::BinaryFrame)
+    }
+
+    pub fn binary(payload: Vec<u8>) -> WebSocketFrame {
+        WebSocketFrame {
+            header: WebSocketFrameHeader::new_header(payload.len(), OpCode::BinaryFrame),
+            payload: payload,
+            mask: None
+        }
+    }
+
+    // A follow-up fragment of a message started by a text or binary frame with
+    // `fin` unset. Pass `fin = true` for the last fragment.
+    pub fn continuation(payload: Vec<u8>, fin: bool) -> WebSocketFrame {
+        Self::with_fin(OpCode::Continuation, payload, fin)
+    }
+
+    // The payload stays a `Vec<u8>`; these convert without copying where
+    // `bytes` allows it (always into `Bytes`, and out of it when unshared).
+    #[cfg(feature = "bytes")]
+    pub fn from_bytes_payload(opcode: OpCode, data: Bytes, fin: bool) -> WebSocketFrame {
+        Self::with_fin(opcode, Vec::from(data), fin)
+    }
+
+    #[cfg(feature = "bytes")]
+    pub fn into_bytes_payload(self) -> Bytes {
+        Bytes::from(self.payload)
+    }
+
+    // Relayed frames carry a message id as a payload prefix: a two byte flag
+    // followed by the 16 byte id, big-endian.
+    #[cfg(feature = "cluster")]
+    pub fn with_message_id(mut self, id: u128) -> WebSocketFrame {
+        let mut payload = Vec::with_capacity(MESSAGE_ID_FLAG.len() + MESSAGE_ID_LEN + self.payload.len());
+        payload.extend_from_slice(&MESSAGE_ID_FLAG);
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.append(&mut self.payload);
+        self.payload = payload;
+        self
+    }
+
+    #[cfg(feature = "cluster")]
+    pub fn message_id(&self) -> Option<u128> {
+        let prefix_len = MESSAGE_ID_FLAG.len() + MESSAGE_ID_LEN;
+        if self.payload.len() < prefix_len || self.payload[..MESSAGE_ID_FLAG.len()] != MESSAGE_ID_FLAG {
+            return None;
+        }
+        let mut id = [0; MESSAGE_ID_LEN];
+        id.copy_from_slice(&self.payload[MESSAGE_ID_FLAG.len()..prefix_len]);
+        Some(u128::from_be_bytes(id))
+    }
+
+    // Strips the message id prefix, if there is one, and returns the id.
+    #[cfg(feature = "cluster")]
+    pub fn take_message_id(&mut self) -> Option<u128> {
+        let id = self.message_id()?;
+        self.payload.drain(..MESSAGE_ID_FLAG.len() + MESSAGE_ID_LEN);
+        Some(id)
+    }
+
+    pub fn ping(payload: Vec<u8>) -> WebSocketFrame {
+        Self::with_fin(OpCode::Ping, payload, true)
+    }
+
+    // A pong that doesn't answer a ping, usable as a one-way heartbeat.
+    pub fn unsolicited_pong(payload: Vec<u8>) -> WebSocketFrame {
+        Self::with_fin(OpCode::Pong, payload, true)
+    }
+
     pub fn pong(ping_frame: &WebSocketFrame) -> WebSocketFrame {
         let payload = ping_frame.payload.clone();
         WebSocketFrame {
@@ -194,14 +541,24 @@ impl WebSocketFrame {
         }
     }
 
+    pub fn close(code: CloseCode, reason: &str) -> WebSocketFrame {
+        let mut body = Vec::with_capacity(2 + reason.len());
+        body.write_u16::<BigEndian>(code.into()).unwrap();
+        body.extend(reason.as_bytes());
+
+        WebSocketFrame {
+            header: WebSocketFrameHeader::new_header(body.len(), OpCode::ConnectionClose),
+            payload: body,
+            mask: None
+        }
+    }
+
     pub fn close_from(recv_frame: &WebSocketFrame) -> WebSocketFrame {
-        let body = if recv_frame.payload.len() > 0 {
-            let status_code = &recv_frame.payload[0..2];
-            let mut body = Vec::with_capacity(2);
-            body.write(status_code);
-            body
-        } else {
-            Vec::new()
+        let body = match recv_frame.payload.len() {
+            0 => Vec::new(),
+            // A close payload must be empty or start with a two byte status code.
+            1 => return Self::close(CloseCode::ProtocolError, ""),
+            _ => recv_frame.payload[0..2].to_vec()
         };
 
         WebSocketFrame {
@@ -214,4 +571,226 @@ impl WebSocketFrame {
     pub fn is_close(&self) -> bool {
         self.header.opcode == OpCode::ConnectionClose
     }
+
+    pub fn close_code(&self) -> Option<CloseCode> {
+        if !self.is_close() || self.payload.len() < 2 {
+            return None;
+        }
+        Some(CloseCode::from(((self.payload[0] as u16) << 8) | self.payload[1] as u16))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn write_to_vec(frame: &WebSocketFrame) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        frame.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> WebSocketFrame {
+        WebSocketFrame::read(&mut &bytes[..]).unwrap()
+    }
+
+    fn assert_same(sent: &WebSocketFrame, received: &WebSocketFrame) {
+        assert_eq!(sent.get_opcode(), received.get_opcode());
+        assert_eq!(sent.fin(), received.fin());
+        assert_eq!(sent.rsv1(), received.rsv1());
+        assert_eq!(sent.rsv2(), received.rsv2());
+        assert_eq!(sent.rsv3(), received.rsv3());
+        assert_eq!(sent.header_payload_length(), received.header_payload_length());
+        assert_eq!(sent.payload, received.payload);
+    }
+
+    #[test]
+    fn small_payload_uses_direct_length() {
+        let frame = WebSocketFrame::from("hello");
+        let bytes = write_to_vec(&frame);
+
+        assert_eq!(bytes.len(), 2 + 5);
+        assert_eq!(bytes[1], 5);
+        assert_same(&frame, &from_bytes(&bytes));
+    }
+
+    #[test]
+    fn medium_payload_uses_u16_length() {
+        let frame = WebSocketFrame::binary(vec![7; 1000]);
+        let bytes = write_to_vec(&frame);
+
+        assert_eq!(frame.header_payload_length(), PAYLOAD_LEN_U16);
+        assert_eq!(bytes.len(), 2 + 2 + 1000);
+        assert_eq!(&bytes[2..4], &[0x03, 0xe8]);
+        assert_same(&frame, &from_bytes(&bytes));
+    }
+
+    #[test]
+    fn large_payload_uses_u64_length() {
+        let frame = WebSocketFrame::binary(vec![9; 70000]);
+        let bytes = write_to_vec(&frame);
+
+        assert_eq!(frame.header_payload_length(), PAYLOAD_LEN_U64);
+        assert_eq!(bytes.len(), 2 + 8 + 70000);
+        assert_same(&frame, &from_bytes(&bytes));
+    }
+
+    #[test]
+    fn masked_client_frame_is_unmasked_on_read() {
+        let frame = WebSocketFrame::from("masked hello");
+        let mask = [0xde, 0xad, 0xbe, 0xef];
+        let mut bytes = Vec::new();
+        frame.write_masked(mask, &mut bytes).unwrap();
+
+        assert_eq!(bytes[1] & 0x80, 0x80);
+        assert_eq!(&bytes[2..6], &mask);
+        assert!(bytes[6..] != frame.payload[..]);
+
+        let received = from_bytes(&bytes);
+        assert!(received.is_masked());
+        assert_same(&frame, &received);
+    }
+
+    #[test]
+    fn ping_is_echoed_as_pong() {
+        let ping = WebSocketFrame::ping(b"are you there?".to_vec());
+        let received = from_bytes(&write_to_vec(&ping));
+        assert_same(&ping, &received);
+
+        let pong = WebSocketFrame::pong(&received);
+        assert_eq!(pong.get_opcode(), OpCode::Pong);
+
+        let received_pong = from_bytes(&write_to_vec(&pong));
+        assert_same(&pong, &received_pong);
+        assert_eq!(received_pong.payload, ping.payload);
+    }
+
+    #[test]
+    fn peek_len_needs_the_whole_header() {
+        let mut masked = Vec::new();
+        WebSocketFrame::binary(vec![0; 300]).write_masked([1, 2, 3, 4], &mut masked).unwrap();
+        assert_eq!(WebSocketFrame::peek_len(&masked[..3]), None);
+        assert_eq!(WebSocketFrame::peek_len(&masked[..4]), Some((8, 300)));
+        assert_eq!(WebSocketFrame::peek_len(&write_to_vec(&WebSocketFrame::from("hi"))), Some((2, 2)));
+    }
+
+    #[test]
+    fn close_code_conversions() {
+        assert_eq!(CloseCode::Normal.to_string(), "1000 Normal Closure");
+        assert_eq!(CloseCode::GoingAway.to_string(), "1001 Going Away");
+        assert_eq!(CloseCode::from(1009), CloseCode::MessageTooBig);
+        assert_eq!(CloseCode::from(4001), CloseCode::Custom(4001));
+        assert_eq!(u16::from(CloseCode::Custom(4001)), 4001);
+
+        let close = from_bytes(&write_to_vec(&WebSocketFrame::close(CloseCode::Custom(4001), "bye")));
+        assert_eq!(close.close_code(), Some(CloseCode::Custom(4001)));
+    }
+
+    #[test]
+    fn close_from_echoes_the_status_code() {
+        let empty = WebSocketFrame::close_from(&WebSocketFrame::with_fin(OpCode::ConnectionClose, Vec::new(), true));
+        assert!(empty.is_close());
+        assert!(empty.payload.is_empty());
+        assert_eq!(empty.header_payload_length(), 0);
+
+        let truncated = WebSocketFrame::close_from(&WebSocketFrame::with_fin(OpCode::ConnectionClose, vec![0x03], true));
+        assert_eq!(truncated.close_code(), Some(CloseCode::ProtocolError));
+
+        let received = WebSocketFrame::close(CloseCode::GoingAway, "moving on");
+        let echoed = WebSocketFrame::close_from(&received);
+        assert_eq!(echoed.payload, vec![0x03, 0xe9]);
+        assert_eq!(echoed.close_code(), Some(CloseCode::GoingAway));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes_payload_round_trip() {
+        let frame = WebSocketFrame::from(Bytes::from_static(b"shared"));
+        assert_eq!(frame.get_opcode(), OpCode::BinaryFrame);
+        assert!(frame.fin());
+
+        let fragment = WebSocketFrame::from_bytes_payload(OpCode::TextFrame, Bytes::from_static(b"part"), false);
+        assert!(!fragment.fin());
+        assert_same(&fragment, &from_bytes(&write_to_vec(&fragment)));
+        assert_eq!(fragment.into_bytes_payload(), Bytes::from_static(b"part"));
+    }
+
+    #[cfg(feature = "cluster")]
+    #[test]
+    fn message_id_prefix_round_trip() {
+        let frame = WebSocketFrame::binary(b"relayed".to_vec()).with_message_id(0xfeed);
+        let mut received = from_bytes(&write_to_vec(&frame));
+        assert_eq!(received.payload.len(), 2 + 16 + 7);
+        assert_eq!(received.take_message_id(), Some(0xfeed));
+        assert_eq!(received.payload, b"relayed");
+
+        assert_eq!(received.take_message_id(), None);
+        assert_eq!(received.payload, b"relayed");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_round_trip_matches_blocking_read() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            for &len in &[5, 300, 70_000] {
+                let frame = WebSocketFrame::binary(vec![0x5a; len]);
+                let mut bytes = Vec::new();
+                frame.write_async(&mut bytes).await.unwrap();
+                assert_eq!(bytes, write_to_vec(&frame));
+
+                let received = WebSocketFrame::read_async(&mut &bytes[..]).await.unwrap();
+                assert_same(&frame, &received);
+            }
+
+            let mut masked = Vec::new();
+            WebSocketFrame::from("masked").write_masked([1, 2, 3, 4], &mut masked).unwrap();
+            let received = WebSocketFrame::read_async(&mut &masked[..]).await.unwrap();
+            assert_eq!(received.payload, b"masked");
+            assert_eq!(received.mask(), Some([1, 2, 3, 4]));
+        });
+    }
+
+    fn any_opcode() -> impl Strategy<Value = OpCode> {
+        prop_oneof![
+            Just(OpCode::Continuation),
+            Just(OpCode::TextFrame),
+            Just(OpCode::BinaryFrame),
+            Just(OpCode::ConnectionClose),
+            Just(OpCode::Ping),
+            Just(OpCode::Pong)
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn serialization_round_trips(opcode in any_opcode(),
+                                     payload in proptest::collection::vec(any::<u8>(), 0..128 * 1024),
+                                     mask in proptest::option::of(any::<[u8; 4]>()),
+                                     fin in any::<bool>()) {
+            let frame = WebSocketFrame::with_fin(opcode, payload, fin);
+            let mut bytes = Vec::new();
+            match mask {
+                Some(mask) => frame.write_masked(mask, &mut bytes).unwrap(),
+                None => frame.write(&mut bytes).unwrap()
+            }
+
+            let received = from_bytes(&bytes);
+            prop_assert_eq!(received.get_opcode(), opcode);
+            prop_assert_eq!(received.fin(), fin);
+            prop_assert_eq!(received.is_masked(), mask.is_some());
+            prop_assert_eq!(received.header_payload_length(), frame.header_payload_length());
+            prop_assert_eq!(received.payload, frame.payload);
+        }
+
+        #[test]
+        fn masking_twice_is_identity(mask in any::<[u8; 4]>(),
+                                     payload in proptest::collection::vec(any::<u8>(), 0..4096)) {
+            let mut bytes = payload.clone();
+            WebSocketFrame::apply_mask_in_place(mask, &mut bytes);
+            WebSocketFrame::apply_mask_in_place(mask, &mut bytes);
+            prop_assert_eq!(bytes, payload);
+        }
+    }
 }
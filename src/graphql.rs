@@ -0,0 +1,140 @@
+use crate::handler::{ConnectionHandler, Message};
+use crate::stats::FrameStats;
+use mio::Token;
+use serde_json::{json, Value};
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+pub const PROTOCOL: &'static str = "graphql-transport-ws";
+
+// Resolves one operation: gets the query and its variables, and returns the
+// payloads to send as `next` messages, or an error message.
+type Resolver = Box<dyn Fn(&str, &Value) -> Result<Vec<Value>, String> + Send + Sync>;
+
+// Speaks the graphql-transport-ws protocol. Every `subscribe` is answered in
+// full right away: the resolver's results go out as `next` messages followed
+// by `complete`.
+pub struct GraphQlWsHandler {
+    resolver: Resolver,
+    initialized: Mutex<HashSet<Token>>
+}
+
+impl GraphQlWsHandler {
+    pub fn new<F>(resolver: F) -> GraphQlWsHandler
+        where F: Fn(&str, &Value) -> Result<Vec<Value>, String> + Send + Sync + 'static
+    {
+        GraphQlWsHandler {
+            resolver: Box::new(resolver),
+            initialized: Mutex::new(HashSet::new())
+        }
+    }
+
+    fn subscribe(&self, id: &str, payload: &Value) -> Vec<Value> {
+        let query = payload["query"].as_str().unwrap_or("");
+        let variables = payload.get("variables").unwrap_or(&Value::Null);
+
+        match (self.resolver)(query, variables) {
+            Ok(results) => {
+                let mut replies: Vec<Value> = results.into_iter()
+                    .map(|result| json!({ "id": id, "type": "next", "payload": result }))
+                    .collect();
+                replies.push(json!({ "id": id, "type": "complete" }));
+                replies
+            },
+            Err(message) => vec![json!({ "id": id, "type": "error", "payload": [{ "message": message }] })]
+        }
+    }
+}
+
+impl ConnectionHandler for GraphQlWsHandler {
+    fn on_message(&self, token: Token, message: Message) -> Vec<Message> {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Binary(_) => return Vec::new()
+        };
+        let message: Value = match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(e) => {
+                println!("invalid graphql-ws message from {:?}: {}", token, e);
+                return Vec::new();
+            }
+        };
+
+        let replies = match message["type"].as_str() {
+            Some("connection_init") => {
+                self.initialized.lock().unwrap().insert(token);
+                vec![json!({ "type": "connection_ack" })]
+            },
+            Some("ping") => vec![json!({ "type": "pong" })],
+            Some("pong") => Vec::new(),
+            Some("subscribe") if !self.initialized.lock().unwrap().contains(&token) => {
+                println!("{:?} subscribed before connection_init", token);
+                Vec::new()
+            },
+            Some("subscribe") => match message["id"].as_str() {
+                Some(id) => self.subscribe(id, &message["payload"]),
+                None => Vec::new()
+            },
+            // Operations finish as soon as they start, so there's nothing to cancel.
+            Some("complete") => Vec::new(),
+            other => {
+                println!("unknown graphql-ws message type from {:?}: {:?}", token, other);
+                Vec::new()
+            }
+        };
+
+        replies.into_iter().map(|reply| Message::Text(reply.to_string())).collect()
+    }
+
+    fn on_close(&self, token: Token, _recv_stats: &FrameStats, _send_stats: &FrameStats) {
+        self.initialized.lock().unwrap().remove(&token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler() -> GraphQlWsHandler {
+        GraphQlWsHandler::new(|query, variables| match query {
+            "{ hello }" => Ok(vec![json!({ "data": { "hello": variables["name"] } })]),
+            _ => Err("unknown query".to_string())
+        })
+    }
+
+    fn send(handler: &GraphQlWsHandler, message: Value) -> Vec<Value> {
+        handler.on_message(Token(1), Message::Text(message.to_string())).into_iter()
+            .map(|reply| match reply {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                Message::Binary(_) => panic!("unexpected binary reply")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn subscribe_is_answered_with_next_and_complete() {
+        let handler = handler();
+        assert_eq!(send(&handler, json!({ "type": "connection_init" })), vec![json!({ "type": "connection_ack" })]);
+
+        let replies = send(&handler, json!({
+            "id": "1", "type": "subscribe",
+            "payload": { "query": "{ hello }", "variables": { "name": "world" } }
+        }));
+        assert_eq!(replies, vec![
+            json!({ "id": "1", "type": "next", "payload": { "data": { "hello": "world" } } }),
+            json!({ "id": "1", "type": "complete" })
+        ]);
+
+        let replies = send(&handler, json!({ "id": "2", "type": "subscribe", "payload": { "query": "{ nope }" } }));
+        assert_eq!(replies[0]["type"], "error");
+    }
+
+    #[test]
+    fn subscribe_requires_connection_init() {
+        let handler = handler();
+        let replies = send(&handler, json!({ "id": "1", "type": "subscribe", "payload": { "query": "{ hello }" } }));
+        assert!(replies.is_empty());
+        assert_eq!(send(&handler, json!({ "type": "ping" })), vec![json!({ "type": "pong" })]);
+    }
+}
@@ -0,0 +1,97 @@
+use crate::error::WebSocketError;
+use crate::frame::{OpCode, WebSocketFrame};
+use mio::Token;
+use crate::stats::FrameStats;
+#[cfg(feature = "tokio")]
+use async_trait::async_trait;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>)
+}
+
+impl Message {
+    pub fn into_frame(self) -> WebSocketFrame {
+        WebSocketFrame::from(self)
+    }
+}
+
+impl From<Message> for WebSocketFrame {
+    fn from(message: Message) -> WebSocketFrame {
+        match message {
+            Message::Text(text) => WebSocketFrame::from(&*text),
+            Message::Binary(data) => WebSocketFrame::binary(data)
+        }
+    }
+}
+
+// Only for unfragmented text and binary frames; anything else is an
+// `InvalidOpcode`.
+impl TryFrom<WebSocketFrame> for Message {
+    type Error = WebSocketError;
+
+    fn try_from(frame: WebSocketFrame) -> Result<Message, WebSocketError> {
+        match frame.get_opcode() {
+            OpCode::TextFrame => String::from_utf8(frame.payload).map(Message::Text).map_err(WebSocketError::InvalidUtf8),
+            OpCode::BinaryFrame => Ok(Message::Binary(frame.payload)),
+            opcode => Err(WebSocketError::InvalidOpcode(opcode as u8))
+        }
+    }
+}
+
+#[cfg_attr(feature = "tokio", async_trait)]
+pub trait ConnectionHandler: Send + Sync + 'static {
+    fn on_message(&self, token: Token, message: Message) -> Vec<Message>;
+
+    // The tokio backend awaits this instead of calling `on_message`. Override it
+    // when replying needs async work; by default it defers to `on_message`.
+    #[cfg(feature = "tokio")]
+    async fn on_message_async(&self, token: Token, message: Message) -> Vec<Message> {
+        self.on_message(token, message)
+    }
+
+    fn on_close(&self, _token: Token, _recv_stats: &FrameStats, _send_stats: &FrameStats) {}
+
+    // Called for requests without an `Upgrade` header. Returns the status code,
+    // content type and body of the response.
+    fn on_http_request(&self, _method: &str, _path: &str,
+                       _headers: &HashMap<String, String>) -> (u16, String, Vec<u8>) {
+        (426, "text/plain".to_string(), b"Upgrade Required".to_vec())
+    }
+}
+
+pub struct GreetingHandler;
+
+impl ConnectionHandler for GreetingHandler {
+    fn on_message(&self, _token: Token, message: Message) -> Vec<Message> {
+        match message {
+            Message::Text(text) => {
+                println!("{}", text);
+                vec![Message::Text("hi there!".to_string())]
+            },
+            Message::Binary(_) => Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_convert_to_and_from_frames() {
+        let frame = WebSocketFrame::from(Message::Text("hi".to_string()));
+        assert_eq!(frame.get_opcode(), OpCode::TextFrame);
+        assert_eq!(Message::try_from(frame).unwrap(), Message::Text("hi".to_string()));
+        assert_eq!(Message::try_from(WebSocketFrame::binary(vec![0xff])).unwrap(), Message::Binary(vec![0xff]));
+
+        let mut invalid = WebSocketFrame::from("");
+        invalid.payload = vec![0xff];
+        assert!(matches!(Message::try_from(invalid), Err(WebSocketError::InvalidUtf8(_))));
+        assert!(matches!(Message::try_from(WebSocketFrame::ping(Vec::new())), Err(WebSocketError::InvalidOpcode(9))));
+    }
+}
@@ -0,0 +1,76 @@
+use crate::error::WebSocketError;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+use std::collections::HashMap;
+
+pub fn gen_key(key: &String) -> String {
+    let mut sha = sha1_smol::Sha1::new();
+
+    sha.update(key.as_bytes());
+    sha.update("258EAFA5-E914-47DA-95CA-C5AB0DC85B11".as_bytes());
+
+    STANDARD.encode(sha.digest().bytes())
+}
+
+// The `101 Switching Protocols` reply to a client's upgrade request.
+pub fn accept_response(key: &String) -> String {
+    format!("HTTP/1.1 101 Switching Protocols\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\
+             Upgrade: websocket\r\n\r\n", gen_key(key))
+}
+
+fn invalid(reason: &str) -> WebSocketError {
+    WebSocketError::InvalidHandshake(reason.to_string())
+}
+
+fn find_header<'a>(headers: &'a HashMap<String, String>, name: &str) -> Option<&'a String> {
+    headers.iter()
+        .find(|&(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+// Checks the upgrade response a server sends back to our client handshake.
+pub fn validate_server_response(response: &[u8], expected_accept: &str)
+                                -> Result<HashMap<String, String>, WebSocketError> {
+    let end = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return Err(invalid("incomplete response"))
+    };
+    let head = String::from_utf8_lossy(&response[..end]);
+    let mut lines = head.split("\r\n");
+
+    let status_line = lines.next().unwrap_or("");
+    let mut status = status_line.split_whitespace();
+    if !status.next().is_some_and(|version| version.starts_with("HTTP/")) {
+        return Err(invalid("malformed status line"));
+    }
+    if status.next() != Some("101") {
+        return Err(WebSocketError::InvalidHandshake(format!("unexpected status: {}", status_line)));
+    }
+
+    let mut headers = HashMap::new();
+    for line in lines {
+        match line.find(':') {
+            Some(colon) => {
+                headers.insert(line[..colon].trim().to_string(), line[colon + 1..].trim().to_string());
+            },
+            None => return Err(invalid("malformed header line"))
+        }
+    }
+
+    if !find_header(&headers, "Upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket")) {
+        return Err(invalid("missing Upgrade: websocket"));
+    }
+    let connection_upgrade = find_header(&headers, "Connection")
+        .is_some_and(|v| v.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")));
+    if !connection_upgrade {
+        return Err(invalid("missing Connection: Upgrade"));
+    }
+    if find_header(&headers, "Sec-WebSocket-Accept").map(|v| v.as_str()) != Some(expected_accept) {
+        return Err(invalid("Sec-WebSocket-Accept mismatch"));
+    }
+
+    Ok(headers)
+}
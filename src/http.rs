@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::str;
+
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&String> {
+        self.headers.iter()
+            .find(|&(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    // True when the client asks to switch protocols: an `Upgrade` header plus
+    // an `upgrade` token in `Connection`.
+    pub fn is_upgrade(&self) -> bool {
+        let connection_upgrade = self.header("Connection")
+            .is_some_and(|v| v.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")));
+        connection_upgrade && self.header("Upgrade").is_some()
+    }
+}
+
+pub enum ParseResult {
+    Incomplete,
+    Complete(HttpRequest),
+    Error(String)
+}
+
+// Parses the request line and headers out of everything received so far.
+// Callers keep appending to the same buffer and call this again until it
+// stops returning `Incomplete`; any request body is ignored.
+pub fn parse(buf: &[u8]) -> ParseResult {
+    let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return ParseResult::Incomplete
+    };
+    let head = match str::from_utf8(&buf[..end]) {
+        Ok(head) => head,
+        Err(_) => return ParseResult::Error("request head is not valid UTF-8".to_string())
+    };
+
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    if parts.len() != 3 || parts[0].is_empty() || parts[1].is_empty() {
+        return ParseResult::Error(format!("malformed request line: {}", request_line));
+    }
+    if !parts[0].bytes().all(|b| b.is_ascii_uppercase()) {
+        return ParseResult::Error(format!("invalid method: {}", parts[0]));
+    }
+    if !parts[2].starts_with("HTTP/1.") {
+        return ParseResult::Error(format!("unsupported version: {}", parts[2]));
+    }
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for line in lines {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            return ParseResult::Error("obsolete header line folding".to_string());
+        }
+        let colon = match line.find(':') {
+            Some(colon) => colon,
+            None => return ParseResult::Error(format!("malformed header: {}", line))
+        };
+        let name = &line[..colon];
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace()) {
+            return ParseResult::Error(format!("invalid header name: {}", name));
+        }
+        let value = line[colon + 1..].trim();
+
+        // Repeated headers are combined into a comma separated list.
+        let existing = headers.keys().find(|key| key.eq_ignore_ascii_case(name)).cloned();
+        match existing {
+            Some(key) => {
+                let combined = headers.get_mut(&key).unwrap();
+                combined.push_str(", ");
+                combined.push_str(value);
+            },
+            None => { headers.insert(name.to_string(), value.to_string()); }
+        }
+    }
+
+    ParseResult::Complete(HttpRequest {
+        method: parts[0].to_string(),
+        path: parts[1].to_string(),
+        headers: headers
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPGRADE: &'static [u8] = b"GET /chat HTTP/1.1\r\n\
+                                     Host: localhost\r\n\
+                                     Upgrade: websocket\r\n\
+                                     Connection: keep-alive, Upgrade\r\n\r\n";
+
+    #[test]
+    fn incomplete_until_blank_line() {
+        for end in 0..UPGRADE.len() {
+            match parse(&UPGRADE[..end]) {
+                ParseResult::Incomplete => {},
+                _ => panic!("parsed a partial request of {} bytes", end)
+            }
+        }
+    }
+
+    #[test]
+    fn parses_request_line_and_headers() {
+        match parse(UPGRADE) {
+            ParseResult::Complete(request) => {
+                assert_eq!(request.method, "GET");
+                assert_eq!(request.path, "/chat");
+                assert_eq!(request.header("host").map(|h| h.as_str()), Some("localhost"));
+                assert!(request.is_upgrade());
+            },
+            _ => panic!("expected a complete request")
+        }
+    }
+
+    #[test]
+    fn combines_repeated_headers() {
+        match parse(b"GET / HTTP/1.1\r\nAccept: a\r\naccept: b\r\n\r\n") {
+            ParseResult::Complete(request) => {
+                assert_eq!(request.header("Accept").map(|h| h.as_str()), Some("a, b"));
+                assert!(!request.is_upgrade());
+            },
+            _ => panic!("expected a complete request")
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let bad: &[&[u8]] = &[b"GET /\r\n\r\n",
+                              b"get / HTTP/1.1\r\n\r\n",
+                              b"GET / HTTP/2\r\n\r\n",
+                              b"GET / HTTP/1.1\r\nNo-Colon\r\n\r\n",
+                              b"GET / HTTP/1.1\r\nA: b\r\n folded\r\n\r\n"];
+        for request in bad {
+            match parse(request) {
+                ParseResult::Error(_) => {},
+                _ => panic!("accepted {:?}", String::from_utf8_lossy(request))
+            }
+        }
+    }
+}
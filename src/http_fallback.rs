@@ -0,0 +1,45 @@
+use crate::handler::ConnectionHandler;
+use crate::http::HttpRequest;
+
+use std::sync::Arc;
+
+// Answers plain HTTP requests (no `Upgrade` header) on the WebSocket port, e.g.
+// for long-polling clients that only upgrade later.
+pub struct HttpFallbackHandler {
+    handler: Arc<dyn ConnectionHandler>
+}
+
+impl HttpFallbackHandler {
+    pub fn new(handler: Arc<dyn ConnectionHandler>) -> HttpFallbackHandler {
+        HttpFallbackHandler { handler: handler }
+    }
+
+    pub fn respond(&self, request: &HttpRequest) -> Vec<u8> {
+        let (status, content_type, body) = self.handler.on_http_request(&request.method, &request.path, &request.headers);
+        response(status, &content_type, &body)
+    }
+}
+
+pub fn response(status: u16, content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {} {}\r\n\
+                                Content-Type: {}\r\n\
+                                Content-Length: {}\r\n\
+                                Connection: close\r\n\r\n",
+                               status, reason_phrase(status), content_type, body.len()).into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        426 => "Upgrade Required",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => ""
+    }
+}
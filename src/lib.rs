@@ -0,0 +1,57 @@
+// The code keeps explicit `field: field` initializers and `&'static` constants.
+#![allow(clippy::redundant_field_names, clippy::redundant_static_lifetimes)]
+
+#[cfg(feature = "tokio")]
+extern crate async_trait;
+extern crate base64;
+#[cfg(feature = "bytes")]
+extern crate bytes;
+extern crate byteorder;
+extern crate mio;
+extern crate rand;
+extern crate rayon;
+#[cfg(feature = "graphql")]
+extern crate serde_json;
+extern crate sha1_smol;
+extern crate signal_hook;
+extern crate socket2;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(test)]
+extern crate proptest;
+
+pub mod access_log;
+pub mod buffer_pool;
+pub mod circuit_breaker;
+#[cfg(feature = "cluster")]
+pub mod cluster;
+pub mod config;
+pub mod deflate;
+pub mod error;
+pub mod event_log;
+pub mod extension;
+pub mod filter;
+pub mod frame;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+pub mod handler;
+pub mod handshake;
+pub mod http;
+pub mod http_fallback;
+pub mod mask;
+#[cfg(test)]
+mod mock_socket;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod read_buffer;
+pub mod scheduler;
+#[cfg(feature = "sequence")]
+pub mod sequence;
+pub mod server;
+pub mod session;
+pub mod stats;
+pub mod timer;
+#[cfg(feature = "tokio")]
+pub mod tokio_server;
+pub mod topic;
@@ -0,0 +1,64 @@
+pub fn apply_mask(mask: [u8; 4], bytes: &mut [u8]) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        apply_mask_sse2(mask, bytes);
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        apply_mask_scalar(mask, bytes);
+    }
+}
+
+pub fn apply_mask_scalar(mask: [u8; 4], bytes: &mut [u8]) {
+    for (i, c) in bytes.iter_mut().enumerate() {
+        *c ^= mask[i % 4];
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn apply_mask_sse2(mask: [u8; 4], bytes: &mut [u8]) {
+    use std::arch::x86_64::{__m128i, _mm_loadu_si128, _mm_storeu_si128, _mm_xor_si128};
+
+    let mut wide_mask = [0u8; 16];
+    for (i, b) in wide_mask.iter_mut().enumerate() {
+        *b = mask[i % 4];
+    }
+
+    // The vectorised part is a multiple of 16 bytes, so the tail starts on a
+    // mask boundary and the scalar loop can pick up from index 0.
+    let split = bytes.len() - bytes.len() % 16;
+    let (head, tail) = bytes.split_at_mut(split);
+
+    // SSE2 is part of the x86_64 baseline, and the unaligned load/store
+    // variants are used so any byte slice is fine.
+    unsafe {
+        let key = _mm_loadu_si128(wide_mask.as_ptr() as *const __m128i);
+        for chunk in head.chunks_exact_mut(16) {
+            let ptr = chunk.as_mut_ptr() as *mut __m128i;
+            _mm_storeu_si128(ptr, _mm_xor_si128(_mm_loadu_si128(ptr), key));
+        }
+    }
+
+    apply_mask_scalar(mask, tail);
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn sse2_matches_scalar(mask in any::<[u8; 4]>(),
+                               bytes in proptest::collection::vec(any::<u8>(), 0..300),
+                               offset in 0usize..16) {
+            // Starting part way into the buffer leaves the slice unaligned.
+            let offset = offset.min(bytes.len());
+            let mut scalar = bytes.clone();
+            let mut sse2 = bytes;
+            apply_mask_scalar(mask, &mut scalar[offset..]);
+            apply_mask_sse2(mask, &mut sse2[offset..]);
+            prop_assert_eq!(scalar, sse2);
+        }
+    }
+}
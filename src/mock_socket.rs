@@ -0,0 +1,93 @@
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+// An in-memory stand-in for a non-blocking TCP stream. Each entry in
+// `read_data` is handed out as one chunk (split across reads if the caller's
+// buffer is smaller); once it runs dry, reads fail with `WouldBlock` like a
+// drained socket would. `would_block_after` forces `WouldBlock` after that
+// many successful reads, to exercise partial-read handling. Likewise
+// `write_capacity` is how many more bytes writes take before they fail with
+// `WouldBlock`, like a full send buffer.
+pub struct MockSocket {
+    pub read_data: VecDeque<Vec<u8>>,
+    pub write_data: Vec<u8>,
+    pub would_block_after: Option<usize>,
+    pub write_capacity: Option<usize>,
+    reads: usize
+}
+
+impl MockSocket {
+    pub fn new() -> MockSocket {
+        MockSocket {
+            read_data: VecDeque::new(),
+            write_data: Vec::new(),
+            would_block_after: None,
+            write_capacity: None,
+            reads: 0
+        }
+    }
+
+    pub fn push_read(&mut self, data: &[u8]) {
+        self.read_data.push_back(data.to_vec());
+    }
+}
+
+impl Read for MockSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(limit) = self.would_block_after {
+            if self.reads >= limit {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "mock socket would block"));
+            }
+        }
+
+        let mut chunk = match self.read_data.pop_front() {
+            Some(chunk) => chunk,
+            None => return Err(io::Error::new(io::ErrorKind::WouldBlock, "mock socket would block"))
+        };
+
+        let len = chunk.len().min(buf.len());
+        buf[..len].copy_from_slice(&chunk[..len]);
+        if len < chunk.len() {
+            self.read_data.push_front(chunk.split_off(len));
+        }
+        self.reads += 1;
+        Ok(len)
+    }
+}
+
+impl Write for MockSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = match self.write_capacity {
+            Some(0) => return Err(io::Error::new(io::ErrorKind::WouldBlock, "mock socket would block")),
+            Some(ref mut capacity) => {
+                let len = buf.len().min(*capacity);
+                *capacity -= len;
+                len
+            },
+            None => buf.len()
+        };
+        self.write_data.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Source for MockSocket {
+    fn register(&mut self, _registry: &Registry, _token: Token, _interests: Interest) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&mut self, _registry: &Registry, _token: Token, _interests: Interest) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&mut self, _registry: &Registry) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+use mio::event::Event;
+use mio::net::TcpStream;
+use mio::{Interest, Token};
+use crate::error::WebSocketError;
+use crate::handshake;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{self, SocketAddr};
+use std::time::Duration;
+
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_TUNNEL_RESPONSE_LEN: usize = 8192;
+
+pub enum UpstreamState {
+    Handshaking,
+    Open
+}
+
+pub struct Upstream {
+    pub socket: TcpStream,
+    pub peer: Token,
+    pub state: UpstreamState,
+    pub to_upstream: Vec<u8>,
+    pub to_client: Vec<u8>,
+    response: Vec<u8>,
+    expected_accept: String
+}
+
+impl Upstream {
+    pub fn connect(address: &SocketAddr, peer: Token) -> io::Result<Upstream> {
+        Ok(Upstream::with_socket(TcpStream::connect(*address)?, &address.to_string(), peer, None))
+    }
+
+    // Starts the upgrade over an already connected socket, e.g. one returned
+    // by `tunnel_through_proxy`. `host` goes into the `Host` header, and
+    // `protocol`, if any, is requested with `Sec-WebSocket-Protocol`.
+    pub fn with_socket(socket: TcpStream, host: &str, peer: Token, protocol: Option<&str>) -> Upstream {
+        let key = STANDARD.encode(rand::random::<[u8; 16]>());
+        let mut request = format!("GET / HTTP/1.1\r\n\
+                                   Host: {}\r\n\
+                                   Upgrade: websocket\r\n\
+                                   Connection: Upgrade\r\n\
+                                   Sec-WebSocket-Key: {}\r\n\
+                                   Sec-WebSocket-Version: 13\r\n", host, key);
+        if let Some(protocol) = protocol {
+            request.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+        }
+        request.push_str("\r\n");
+
+        Upstream {
+            socket: socket,
+            peer: peer,
+            state: UpstreamState::Handshaking,
+            to_upstream: request.into_bytes(),
+            to_client: Vec::new(),
+            response: Vec::new(),
+            expected_accept: handshake::gen_key(&key)
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(self.state, UpstreamState::Open)
+    }
+
+    // Returns `Ok(false)` once the upstream has closed its end of the connection.
+    pub fn read(&mut self) -> io::Result<bool> {
+        match self.state {
+            UpstreamState::Open => read_available(&mut self.socket, &mut self.to_client),
+            UpstreamState::Handshaking => {
+                let open = read_available(&mut self.socket, &mut self.response)?;
+
+                let header_end = self.response.windows(4).position(|w| w == b"\r\n\r\n");
+                if let Some(end) = header_end {
+                    if let Err(e) = handshake::validate_server_response(&self.response[..end + 4],
+                                                                        &self.expected_accept) {
+                        return Err(io::Error::other(e.to_string()));
+                    }
+
+                    self.to_client.extend_from_slice(&self.response[end + 4..]);
+                    self.response.clear();
+                    self.state = UpstreamState::Open;
+                }
+
+                Ok(open)
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        flush(&mut self.socket, &mut self.to_upstream)
+    }
+
+    // Interest for the proxied client and for the upstream socket respectively.
+    pub fn interests(&self) -> (Interest, Interest) {
+        let mut client = Interest::READABLE;
+        if !self.to_client.is_empty() {
+            client |= Interest::WRITABLE;
+        }
+
+        let mut upstream = Interest::READABLE;
+        if !self.to_upstream.is_empty() {
+            upstream |= Interest::WRITABLE;
+        }
+
+        (client, upstream)
+    }
+}
+
+// Opens a tunnel to `target_host:target_port` through an HTTP proxy with
+// `CONNECT`. This blocks until the proxy answers; the returned socket is
+// non-blocking and ready for the WebSocket handshake.
+pub fn tunnel_through_proxy(proxy_addr: &SocketAddr, target_host: &str, target_port: u16,
+                            credentials: Option<(&str, &str)>) -> Result<TcpStream, WebSocketError> {
+    let mut socket = net::TcpStream::connect_timeout(proxy_addr, TUNNEL_TIMEOUT)?;
+    socket.set_read_timeout(Some(TUNNEL_TIMEOUT))?;
+
+    // IPv6 literals need brackets to tell the address from the port.
+    let authority = if target_host.contains(':') && !target_host.starts_with('[') {
+        format!("[{}]:{}", target_host, target_port)
+    } else {
+        format!("{}:{}", target_host, target_port)
+    };
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some((user, password)) = credentials {
+        let encoded = STANDARD.encode(format!("{}:{}", user, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", encoded));
+    }
+    request.push_str("\r\n");
+    socket.write_all(request.as_bytes())?;
+
+    // Read a byte at a time so nothing past the proxy's response is consumed.
+    let mut response = Vec::new();
+    let mut byte = [0; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_TUNNEL_RESPONSE_LEN {
+            return Err(WebSocketError::InvalidHandshake("proxy response too long".to_string()));
+        }
+        if socket.read(&mut byte)? == 0 {
+            return Err(WebSocketError::from(io::Error::from(ErrorKind::UnexpectedEof)));
+        }
+        response.push(byte[0]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or("");
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        return Err(WebSocketError::InvalidHandshake(format!("proxy refused CONNECT: {}", status_line)));
+    }
+
+    socket.set_read_timeout(None)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpStream::from_std(socket))
+}
+
+// Moves whatever is available between the client and the upstream. Returns
+// `Ok(false)` when either side has gone away.
+pub fn relay(client: &mut TcpStream, upstream: &mut Upstream, from_upstream: bool,
+             event: &Event) -> io::Result<bool> {
+    if event.is_error() || event.is_write_closed() {
+        return Ok(false);
+    }
+
+    let open = if !event.is_readable() {
+        true
+    } else if from_upstream {
+        upstream.read()?
+    } else {
+        read_available(client, &mut upstream.to_upstream)?
+    };
+
+    upstream.flush()?;
+    flush(client, &mut upstream.to_client)?;
+    Ok(open)
+}
+
+pub fn read_available(socket: &mut TcpStream, buf: &mut Vec<u8>) -> io::Result<bool> {
+    let mut chunk = [0; 4096];
+    loop {
+        match socket.read(&mut chunk) {
+            Ok(0) => return Ok(false),
+            Ok(len) => buf.extend_from_slice(&chunk[..len]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+pub fn flush(socket: &mut TcpStream, buf: &mut Vec<u8>) -> io::Result<()> {
+    while !buf.is_empty() {
+        match socket.write(buf) {
+            Ok(len) => { buf.drain(..len); },
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Accepts one connection, checks the CONNECT request and answers with `response`.
+    fn fake_proxy(response: &'static [u8]) -> (SocketAddr, thread::JoinHandle<String>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = socket.read(&mut chunk).unwrap();
+                request.extend_from_slice(&chunk[..len]);
+            }
+            socket.write_all(response).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn tunnel_sends_connect_with_credentials() {
+        let (address, proxy) = fake_proxy(b"HTTP/1.1 200 Connection Established\r\n\r\n");
+        tunnel_through_proxy(&address, "example.com", 443, Some(("user", "secret"))).unwrap();
+
+        let request = proxy.join().unwrap();
+        assert!(request.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+    }
+
+    #[test]
+    fn tunnel_brackets_ipv6_targets() {
+        let (address, proxy) = fake_proxy(b"HTTP/1.1 200 Connection Established\r\n\r\n");
+        tunnel_through_proxy(&address, "::1", 8080, None).unwrap();
+
+        let request = proxy.join().unwrap();
+        assert!(request.starts_with("CONNECT [::1]:8080 HTTP/1.1\r\nHost: [::1]:8080\r\n"));
+    }
+
+    #[test]
+    fn tunnel_fails_when_the_proxy_refuses() {
+        let (address, proxy) = fake_proxy(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n");
+        match tunnel_through_proxy(&address, "example.com", 443, None) {
+            Err(WebSocketError::InvalidHandshake(reason)) => assert!(reason.contains("407")),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("tunnel should have failed")
+        }
+        assert!(!proxy.join().unwrap().contains("Proxy-Authorization"));
+    }
+}
@@ -0,0 +1,98 @@
+use crate::error::WebSocketError;
+
+use std::net::{IpAddr, SocketAddr};
+use std::str;
+
+// A v1 header, including the trailing CRLF, is never longer than this.
+pub const MAX_HEADER_LEN: usize = 107;
+
+// Parses a PROXY protocol v1 header from the start of `buf`. Returns `None` if
+// the header is not complete yet, otherwise the number of bytes it occupied and
+// the source address it carried (`None` for `UNKNOWN` connections).
+pub fn parse(buf: &[u8]) -> Result<Option<(usize, Option<SocketAddr>)>, WebSocketError> {
+    let end = match buf.windows(2).take(MAX_HEADER_LEN - 1).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() >= MAX_HEADER_LEN => return Err(invalid("header too long")),
+        None => return Ok(None)
+    };
+
+    let line = str::from_utf8(&buf[..end]).map_err(|_| invalid("header is not ASCII"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    if parts[0] != "PROXY" || parts.len() < 2 {
+        return Err(invalid("missing PROXY signature"));
+    }
+
+    let source = match parts[1] {
+        "UNKNOWN" => None,
+        family @ "TCP4" | family @ "TCP6" => {
+            if parts.len() != 6 {
+                return Err(invalid("wrong number of fields"));
+            }
+
+            let ip = parts[2].parse::<IpAddr>().map_err(|_| invalid("bad source address"))?;
+            parts[3].parse::<IpAddr>().map_err(|_| invalid("bad destination address"))?;
+            let port = parts[4].parse::<u16>().map_err(|_| invalid("bad source port"))?;
+            parts[5].parse::<u16>().map_err(|_| invalid("bad destination port"))?;
+
+            match (family, ip) {
+                ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => {},
+                _ => return Err(invalid("address does not match protocol family"))
+            }
+
+            Some(SocketAddr::new(ip, port))
+        },
+        _ => return Err(invalid("unsupported protocol family"))
+    };
+
+    Ok(Some((end + 2, source)))
+}
+
+fn invalid(reason: &str) -> WebSocketError {
+    WebSocketError::InvalidProxyHeader(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_invalid(buf: &[u8]) {
+        match parse(buf) {
+            Err(WebSocketError::InvalidProxyHeader(_)) => {},
+            other => panic!("expected InvalidProxyHeader, got {:?}", other.map_err(|e| e.to_string()))
+        }
+    }
+
+    #[test]
+    fn tcp4_header_gives_the_source_address() {
+        let buf = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let source = "192.0.2.1:56324".parse().unwrap();
+        assert_eq!(parse(buf).unwrap(), Some((45, Some(source))));
+    }
+
+    #[test]
+    fn tcp6_header_gives_the_source_address() {
+        let buf = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        let source = "[2001:db8::1]:56324".parse().unwrap();
+        assert_eq!(parse(buf).unwrap(), Some((buf.len(), Some(source))));
+    }
+
+    #[test]
+    fn unknown_header_has_no_source_address() {
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").unwrap(), Some((15, None)));
+    }
+
+    #[test]
+    fn truncated_header_waits_for_more() {
+        assert_eq!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56").unwrap(), None);
+        assert_invalid(&[b'P'; MAX_HEADER_LEN]);
+    }
+
+    #[test]
+    fn malformed_addresses_are_rejected() {
+        assert_invalid(b"PROXY TCP4 192.0.2.300 198.51.100.1 56324 443\r\n");
+        assert_invalid(b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 443\r\n");
+        assert_invalid(b"PROXY TCP6 2001:db8::1 2001:db8::2 70000 443\r\n");
+        assert_invalid(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n");
+    }
+}
@@ -0,0 +1,37 @@
+use std::time::Instant;
+
+pub struct TokenBucket {
+    capacity: u32,
+    tokens: f64,
+    rate: f64,
+    last_refill: Instant
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: u32) -> TokenBucket {
+        TokenBucket {
+            capacity: burst,
+            tokens: burst as f64,
+            rate: rate,
+            last_refill: Instant::now()
+        }
+    }
+
+    pub fn consume(&mut self) -> bool {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity as f64);
+        self.last_refill = now;
+    }
+}
@@ -0,0 +1,202 @@
+use crate::buffer_pool::BufferPool;
+use crate::error::WebSocketError;
+use crate::frame::WebSocketFrame;
+
+use std::io::{self, ErrorKind, Read};
+
+const INITIAL_CAPACITY: usize = 4096;
+// Two bytes of flags and length, an 8-byte extended length and the mask.
+const MAX_HEADER_LEN: usize = 14;
+
+// Bytes read off a socket that haven't been parsed into frames yet. The
+// allocation is reused between reads; when it fills up it doubles, but never
+// past what the largest allowed frame needs.
+pub struct CircularReadBuffer {
+    buf: Box<[u8]>,
+    read_pos: usize,
+    write_pos: usize,
+    len: usize,
+    max_capacity: usize
+}
+
+impl CircularReadBuffer {
+    pub fn new(max_payload_len: usize) -> CircularReadBuffer {
+        CircularReadBuffer {
+            buf: Box::new([]),
+            read_pos: 0,
+            write_pos: 0,
+            len: 0,
+            max_capacity: max_payload_len.saturating_add(MAX_HEADER_LEN)
+        }
+    }
+
+    // Frames already buffered are kept; a smaller limit only stops the buffer
+    // from growing further.
+    pub fn set_max_payload_len(&mut self, max_payload_len: usize) {
+        self.max_capacity = max_payload_len.saturating_add(MAX_HEADER_LEN);
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.read_pos = 0;
+        self.write_pos = 0;
+        self.len = 0;
+    }
+
+    // Reads until the socket would block or `limit` bytes have been read.
+    // Returns true if the peer has closed its end.
+    pub fn feed_from_socket<R: Read>(&mut self, socket: &mut R, limit: usize) -> io::Result<bool> {
+        let mut total = 0;
+        while total < limit {
+            if self.len == self.buf.len() && !self.grow() {
+                break;
+            }
+
+            // The free space up to the end of the allocation or the unread bytes.
+            let end = if self.write_pos >= self.read_pos { self.buf.len() } else { self.read_pos };
+            let end = end.min(self.write_pos.saturating_add(limit - total));
+            match socket.read(&mut self.buf[self.write_pos..end]) {
+                Ok(0) => return Ok(true),
+                Ok(len) => {
+                    self.write_pos = (self.write_pos + len) % self.buf.len();
+                    self.len += len;
+                    total += len;
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(false)
+    }
+
+    // Takes the next frame off the buffer, reading its payload into a buffer
+    // from `pool`. Returns `Ok(None)` until the whole frame has arrived.
+    pub fn try_parse_frame(&mut self, max_payload_len: usize,
+                           pool: &mut BufferPool) -> Result<Option<WebSocketFrame>, WebSocketError> {
+        let mut header = [0; MAX_HEADER_LEN];
+        let header_len = self.peek(&mut header);
+        let (header_len, payload_len) = match WebSocketFrame::peek_len(&header[..header_len]) {
+            Some(lens) => lens,
+            None => return Ok(None)
+        };
+        if payload_len > max_payload_len {
+            return Err(WebSocketError::PayloadTooLarge { limit: max_payload_len, actual: payload_len });
+        }
+        if self.len < header_len + payload_len {
+            return Ok(None);
+        }
+
+        let (front, back) = self.slices();
+        let frame = WebSocketFrame::read_limited_into(&mut front.chain(back), max_payload_len, pool.take());
+        self.consume(header_len + payload_len);
+        frame.map(Some)
+    }
+
+    // The unread bytes, in order; the second slice is non-empty once they wrap around.
+    fn slices(&self) -> (&[u8], &[u8]) {
+        if self.len == 0 {
+            (&[], &[])
+        } else if self.read_pos < self.write_pos {
+            (&self.buf[self.read_pos..self.write_pos], &[])
+        } else {
+            (&self.buf[self.read_pos..], &self.buf[..self.write_pos])
+        }
+    }
+
+    // Copies as many unread bytes as fit into `out` without consuming them.
+    fn peek(&self, out: &mut [u8]) -> usize {
+        let (front, back) = self.slices();
+        let len = out.len().min(self.len);
+        let from_front = len.min(front.len());
+        out[..from_front].copy_from_slice(&front[..from_front]);
+        out[from_front..len].copy_from_slice(&back[..len - from_front]);
+        len
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.len -= len;
+        if self.len == 0 {
+            self.clear();
+        } else {
+            self.read_pos = (self.read_pos + len) % self.buf.len();
+        }
+    }
+
+    // Returns false if the buffer is already as large as it may get.
+    fn grow(&mut self) -> bool {
+        if self.buf.len() >= self.max_capacity {
+            return false;
+        }
+        let capacity = (self.buf.len() * 2).max(INITIAL_CAPACITY).min(self.max_capacity);
+
+        let mut buf = vec![0; capacity].into_boxed_slice();
+        let (front, back) = self.slices();
+        buf[..front.len()].copy_from_slice(front);
+        buf[front.len()..self.len].copy_from_slice(back);
+
+        self.buf = buf;
+        self.read_pos = 0;
+        self.write_pos = self.len % capacity;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masked(payload: Vec<u8>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        WebSocketFrame::binary(payload).write_masked([1, 2, 3, 4], &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn frames_wrap_around_the_end_of_the_buffer() {
+        let mut buffer = CircularReadBuffer::new(INITIAL_CAPACITY - MAX_HEADER_LEN);
+        let mut pool = BufferPool::new(0);
+
+        for byte in 0..3 {
+            let bytes = masked(vec![byte; 3000]);
+            buffer.feed_from_socket(&mut &bytes[..2000], usize::MAX).unwrap();
+            assert!(buffer.try_parse_frame(usize::MAX, &mut pool).unwrap().is_none());
+
+            buffer.feed_from_socket(&mut &bytes[2000..], usize::MAX).unwrap();
+            let frame = buffer.try_parse_frame(usize::MAX, &mut pool).unwrap().unwrap();
+            assert_eq!(frame.payload, vec![byte; 3000]);
+            assert!(buffer.is_empty());
+        }
+        assert_eq!(buffer.capacity(), INITIAL_CAPACITY);
+    }
+
+    #[test]
+    fn grows_until_the_largest_frame_fits() {
+        let mut buffer = CircularReadBuffer::new(10_000);
+        let mut pool = BufferPool::new(0);
+
+        let bytes = masked(vec![7; 10_000]);
+        let mut input = &bytes[..];
+        buffer.feed_from_socket(&mut input, 5000).unwrap();
+        assert_eq!(buffer.capacity(), 8192);
+        buffer.feed_from_socket(&mut input, usize::MAX).unwrap();
+        assert_eq!(buffer.capacity(), 10_000 + MAX_HEADER_LEN);
+        assert_eq!(buffer.try_parse_frame(10_000, &mut pool).unwrap().unwrap().payload.len(), 10_000);
+
+        buffer.feed_from_socket(&mut &masked(vec![7; 10_001])[..], usize::MAX).unwrap();
+        match buffer.try_parse_frame(10_000, &mut pool) {
+            Err(WebSocketError::PayloadTooLarge { limit: 10_000, actual: 10_001 }) => {},
+            other => panic!("expected PayloadTooLarge, got {:?}", other.map(|frame| frame.is_some()))
+        }
+    }
+}
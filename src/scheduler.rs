@@ -0,0 +1,75 @@
+use crate::frame::WebSocketFrame;
+use mio::Token;
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScheduleTarget {
+    Client(Token),
+    Broadcast
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScheduleHandle(u64);
+
+struct ScheduleEntry {
+    handle: ScheduleHandle,
+    target: ScheduleTarget,
+    frame: WebSocketFrame,
+    next_fire: Instant,
+    repeat: Option<Duration>
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    entries: Vec<ScheduleEntry>,
+    next_handle: u64
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            entries: Vec::new(),
+            next_handle: 0
+        }
+    }
+
+    pub fn schedule(&mut self, target: ScheduleTarget, frame: WebSocketFrame, delay: Duration,
+                    repeat: Option<Duration>) -> ScheduleHandle {
+        let handle = ScheduleHandle(self.next_handle);
+        self.next_handle += 1;
+
+        self.entries.push(ScheduleEntry {
+            handle: handle,
+            target: target,
+            frame: frame,
+            next_fire: Instant::now() + delay,
+            repeat: repeat
+        });
+        handle
+    }
+
+    pub fn cancel(&mut self, handle: ScheduleHandle) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.handle != handle);
+        self.entries.len() != before
+    }
+
+    // Takes every entry due at `now`. One-off entries are removed, repeating
+    // entries are pushed forward by their interval.
+    pub fn due(&mut self, now: Instant) -> Vec<(ScheduleTarget, WebSocketFrame)> {
+        let mut fired = Vec::new();
+
+        for entry in self.entries.iter_mut() {
+            if entry.next_fire <= now {
+                fired.push((entry.target, entry.frame.clone()));
+                if let Some(interval) = entry.repeat {
+                    entry.next_fire = now + interval;
+                }
+            }
+        }
+
+        self.entries.retain(|entry| entry.repeat.is_some() || entry.next_fire > now);
+        fired
+    }
+}
@@ -0,0 +1,49 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::frame::WebSocketFrame;
+
+const SEQUENCE_LEN: usize = 8;
+
+// Debugging extension: data frames with RSV2 set carry an 8 byte little-endian
+// sequence number after their payload. Keep one instance per direction.
+#[derive(Default)]
+pub struct SequenceExtension {
+    counter: u64
+}
+
+impl SequenceExtension {
+    pub fn new() -> SequenceExtension {
+        SequenceExtension { counter: 0 }
+    }
+
+    pub fn stamp(&mut self, mut frame: WebSocketFrame) -> WebSocketFrame {
+        if !frame.is_data() {
+            return frame;
+        }
+
+        frame.payload.write_u64::<LittleEndian>(self.counter).unwrap();
+        frame.set_rsv2(true);
+        self.counter += 1;
+        frame
+    }
+
+    pub fn verify(&mut self, mut frame: WebSocketFrame) -> WebSocketFrame {
+        if !frame.is_data() || !frame.rsv2() {
+            return frame;
+        }
+        if frame.payload.len() < SEQUENCE_LEN {
+            println!("warning: frame has RSV2 set but is too short for a sequence number");
+            return frame;
+        }
+
+        let split = frame.payload.len() - SEQUENCE_LEN;
+        let sequence = (&frame.payload[split..]).read_u64::<LittleEndian>().unwrap();
+        if sequence != self.counter {
+            println!("warning: sequence gap, expected {} but got {}", self.counter, sequence);
+        }
+
+        frame.payload.truncate(split);
+        frame.set_rsv2(false);
+        self.counter = sequence.wrapping_add(1);
+        frame
+    }
+}
@@ -0,0 +1,3305 @@
+use crate::{access_log, deflate, event_log, filter, handshake, http, http_fallback, proxy, proxy_protocol, session, topic};
+use crate::access_log::AccessLogEntry;
+use crate::buffer_pool::BufferPool;
+use crate::circuit_breaker::CircuitBreaker;
+#[cfg(feature = "cluster")]
+use crate::cluster::{self, ClusterMessage, SeenMessages};
+use crate::config::ServerConfig;
+use crate::deflate::{CompressionConfig, DeflateParams};
+use crate::error::WebSocketError;
+use crate::event_log::{ConnectionEvent, ConnectionEventKind};
+use crate::extension::ExtensionContext;
+use crate::filter::FrameFilter;
+use crate::frame::{CloseCode, OpCode, WebSocketFrame};
+use crate::handler::{ConnectionHandler, GreetingHandler, Message};
+use crate::http_fallback::HttpFallbackHandler;
+use crate::http::{HttpRequest, ParseResult};
+use crate::proxy::Upstream;
+use crate::rate_limit::TokenBucket;
+use crate::read_buffer::CircularReadBuffer;
+use crate::scheduler::{ScheduleHandle, ScheduleTarget, Scheduler};
+#[cfg(feature = "sequence")]
+use crate::sequence::SequenceExtension;
+use crate::session::{Session, RESUME_TOKEN_HEADER};
+use crate::stats::{FrameStats, Metrics, ServerStats};
+use crate::timer::Timers;
+use mio::*;
+use mio::event::Event;
+use mio::net::*;
+use socket2::{Domain, Socket, Type};
+#[cfg(unix)]
+use signal_hook::consts::SIGUSR1;
+#[cfg(unix)]
+use signal_hook::iterator::Signals;
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{self, IpAddr, Shutdown, SocketAddr};
+use std::process;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+
+const WAKER_TOKEN: Token = Token(usize::MAX);
+const EVENTS_CAPACITY: usize = 1024;
+const MAX_CONTROL_PAYLOAD_LEN: usize = 125;
+const SCHEDULER_INTERVAL_MS: u64 = 1000;
+const BUFFER_POOL_SIZE: usize = 64;
+pub const HANDSHAKE_BUF_LEN: usize = 2048;
+// Most bytes taken off a client's socket per readable event, so one fast
+// sender can't hold up the event loop.
+const READ_CHUNK_LIMIT: usize = 64 * 1024;
+const ERROR_BACKOFF_THRESHOLD: u8 = 5;
+const ERROR_BACKOFF_BASE_MS: u64 = 50;
+const ERROR_BACKOFF_MAX_SHIFT: u8 = 6;
+const DRAIN_TIMEOUT_MS: u64 = 30_000;
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+const DRAINED_MARKER_PATH: &'static str = "/tmp/chat.pid.drained";
+// First byte of the binary system message sent to members of a renamed room,
+// followed by the old and the new name separated by a NUL byte.
+const ROOM_RENAMED_TAG: u8 = 0x01;
+const DEAD_LETTER_CAPACITY: usize = 1000;
+const HEALTH_PATH: &'static str = "/health";
+#[cfg(feature = "cluster")]
+const SEEN_IDS_CAPACITY: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeadLetterReason {
+    // Dropped by the message TTL or the send deadline.
+    Expired,
+    // Still queued when the client went away.
+    Disconnected
+}
+
+struct QueuedFrame {
+    frame: WebSocketFrame,
+    enqueued_at: Instant,
+    expires_at: Option<Instant>
+}
+
+impl QueuedFrame {
+    // Only data frames expire; control frames always go out.
+    fn new(frame: WebSocketFrame, ttl: Option<Duration>) -> QueuedFrame {
+        let now = Instant::now();
+        let expires_at = if frame.is_data() { ttl.map(|ttl| now + ttl) } else { None };
+
+        QueuedFrame {
+            frame: frame,
+            enqueued_at: now,
+            expires_at: expires_at
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at < now)
+    }
+}
+
+enum ClientState {
+    AwaitingHandshake,
+    HandshakeResponse,
+    HttpRequestReceived(http::HttpRequest),
+    HttpResponse,
+    Connected,
+    ClosingSent
+}
+
+pub struct WebSocketClient<S = TcpStream> {
+    socket: S,
+    peer_addr: SocketAddr,
+    config: Arc<ServerConfig>,
+    awaiting_proxy_header: bool,
+    proxy_header_buf: Vec<u8>,
+    request_buf: Vec<u8>,
+    // Method and path of the upgrade request.
+    request_line: Option<(String, String)>,
+    headers: HashMap<String, String>,
+    // Status of the error response the handshake was rejected with.
+    rejected_with: Option<u16>,
+    interest: Interest,
+    state: ClientState,
+    close_code: Option<CloseCode>,
+    resume_token: Option<String>,
+    subprotocol: Option<String>,
+    deflate: Option<DeflateParams>,
+    pending_ping: Option<(Instant, Vec<u8>)>,
+    // Builds the pong payload for a ping; without one the payload is echoed.
+    on_ping: Option<ClientPingHandler>,
+    outgoing: Vec<QueuedFrame>,
+    write_buf: Vec<u8>,
+    expired: Vec<WebSocketFrame>,
+    dropped_frames: usize,
+    draining: bool,
+    incoming: Vec<Message>,
+    // Set while a worker is running the handler on this client's messages;
+    // new ones wait in `incoming` so they are handled in order.
+    dispatching: bool,
+    rate_limiter: Option<TokenBucket>,
+    // Bytes read off the socket that don't make up a whole frame yet, and
+    // since when they have been waiting for the rest.
+    read_buf: CircularReadBuffer,
+    read_started_at: Option<Instant>,
+    // When the last frame arrived, for the idle timeout and keepalive pings.
+    last_read_at: Instant,
+    fragments: Vec<u8>,
+    fragment_opcode: Option<OpCode>,
+    events: VecDeque<ConnectionEvent>,
+    buffer_pool: Rc<RefCell<BufferPool>>,
+    header_bytes_read: usize,
+    handshake_retries_remaining: u8,
+    recv_stats: FrameStats,
+    send_stats: FrameStats,
+    consecutive_errors: u8,
+    flush_scheduled: bool,
+    // Set by `pause_client`: the socket is only polled for writability, and
+    // not registered at all while there is nothing to write.
+    paused: bool,
+    registered: bool,
+    handler: Option<Arc<dyn ConnectionHandler>>,
+    // Applied to incoming frames; outgoing ones are stamped by `send_sequence`.
+    // Both start out empty and are only set up when the config opts in.
+    extensions: ExtensionContext,
+    #[cfg(feature = "sequence")]
+    send_sequence: Option<SequenceExtension>
+}
+
+impl<S: Read + Write + event::Source> WebSocketClient<S> {
+    // Updates the registration to match `interest`, leaving out readability
+    // while paused. mio can't register a socket for nothing, so a paused
+    // client with nothing to write is deregistered instead.
+    fn reregister(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let interest = if self.paused { self.interest.remove(Interest::READABLE) } else { Some(self.interest) };
+        match (interest, self.registered) {
+            (Some(interest), true) => registry.reregister(&mut self.socket, token, interest),
+            (Some(interest), false) => {
+                registry.register(&mut self.socket, token, interest)?;
+                self.registered = true;
+                Ok(())
+            },
+            (None, true) => {
+                registry.deregister(&mut self.socket)?;
+                self.registered = false;
+                Ok(())
+            },
+            (None, false) => Ok(())
+        }
+    }
+}
+
+impl<S: Read + Write> WebSocketClient<S> {
+    fn new(socket: S, peer_addr: SocketAddr) -> WebSocketClient<S> {
+        let config = Arc::new(ServerConfig::default());
+        WebSocketClient {
+            socket: socket,
+            peer_addr: peer_addr,
+            awaiting_proxy_header: false,
+            proxy_header_buf: Vec::new(),
+            request_buf: Vec::new(),
+            request_line: None,
+            headers: HashMap::new(),
+            rejected_with: None,
+            interest: Interest::READABLE,
+            close_code: None,
+            resume_token: None,
+            subprotocol: None,
+            deflate: None,
+            pending_ping: None,
+            on_ping: None,
+            outgoing: Vec::new(),
+            write_buf: Vec::new(),
+            expired: Vec::new(),
+            dropped_frames: 0,
+            draining: false,
+            incoming: Vec::new(),
+            dispatching: false,
+            rate_limiter: None,
+            read_buf: CircularReadBuffer::new(config.max_payload_len),
+            read_started_at: None,
+            last_read_at: Instant::now(),
+            fragments: Vec::new(),
+            fragment_opcode: None,
+            events: VecDeque::new(),
+            buffer_pool: Rc::new(RefCell::new(BufferPool::new(0))),
+            header_bytes_read: 0,
+            handshake_retries_remaining: 0,
+            recv_stats: FrameStats::default(),
+            send_stats: FrameStats::default(),
+            consecutive_errors: 0,
+            flush_scheduled: false,
+            paused: false,
+            registered: true,
+            handler: None,
+            config: config,
+            extensions: ExtensionContext::default(),
+            #[cfg(feature = "sequence")]
+            send_sequence: None,
+            state: ClientState::AwaitingHandshake
+        }
+    }
+
+    fn read(&mut self, filters: &[Box<dyn FrameFilter>]) -> Result<(), WebSocketError> {
+        match self.state {
+            ClientState::AwaitingHandshake => {
+                let result = self.read_handshake();
+                if let Err(ref e) = result {
+                    self.record(ConnectionEventKind::HandshakeFailed(e.to_string()));
+                }
+                result
+            },
+            ClientState::Connected => self.read_frame(filters),
+            ClientState::ClosingSent => self.discard_input(),
+            _ => Ok(())
+        }
+    }
+
+    fn record(&mut self, kind: ConnectionEventKind) {
+        event_log::record(&mut self.events, kind);
+    }
+
+    pub fn event_history(&self) -> &VecDeque<ConnectionEvent> {
+        &self.events
+    }
+
+    fn read_frame(&mut self, filters: &[Box<dyn FrameFilter>]) -> Result<(), WebSocketError> {
+        let peer_closed = self.read_buf.feed_from_socket(&mut self.socket, READ_CHUNK_LIMIT)?;
+
+        let mut frames_read = 0;
+        loop {
+            let parsed = self.read_buf.try_parse_frame(self.config.max_payload_len, &mut self.buffer_pool.borrow_mut());
+            let frame = match parsed {
+                Ok(Some(frame)) => frame,
+                // The rest of the frame hasn't arrived yet.
+                Ok(None) => break,
+                Err(WebSocketError::PayloadTooLarge { limit, actual }) => {
+                    println!("rejecting frame of {} bytes (limit {})", actual, limit);
+                    // The rest of the stream can't be framed any more.
+                    self.read_buf.clear();
+                    self.enqueue(WebSocketFrame::close(CloseCode::MessageTooBig, ""));
+                    self.interest = Interest::WRITABLE;
+                    break;
+                },
+                Err(e) => return Err(e)
+            };
+            frames_read += 1;
+            self.handle_frame(frame, filters);
+        }
+
+        if frames_read > 0 {
+            self.last_read_at = Instant::now();
+        }
+        if self.read_buf.is_empty() {
+            self.read_started_at = None;
+        } else {
+            self.read_started_at.get_or_insert_with(Instant::now);
+        }
+
+        // Frames that came in just before the hang-up are handled first; the
+        // next readable event reports it again.
+        if peer_closed && frames_read == 0 {
+            return Err(WebSocketError::from(io::Error::from(ErrorKind::UnexpectedEof)));
+        }
+        Ok(())
+    }
+
+    fn handle_frame(&mut self, frame: WebSocketFrame, filters: &[Box<dyn FrameFilter>]) {
+        self.recv_stats.record(frame.payload.len());
+
+        let frame = match self.extensions.decode(frame) {
+            Ok(frame) => frame,
+            Err(e) => {
+                println!("closing: {}", e);
+                self.flush_and_close(CloseCode::ProtocolError, "compressed frames are not supported");
+                return;
+            }
+        };
+
+        let frame = match filter::apply_incoming(filters, frame) {
+            Some(frame) => frame,
+            None => return
+        };
+
+        if frame.get_opcode() != OpCode::ConnectionClose {
+            if let Some(ref mut limiter) = self.rate_limiter {
+                if !limiter.consume() {
+                    println!("rate limit exceeded, dropping frame ({})", self.state_name());
+                    self.buffer_pool.borrow_mut().give(frame.payload);
+                    return;
+                }
+            }
+        }
+
+        // Payloads that aren't handed on as a message go back to the pool.
+        let unused_payload = match frame.get_opcode() {
+            opcode @ OpCode::TextFrame | opcode @ OpCode::BinaryFrame => {
+                self.fragments.clear();
+                self.fragment_opcode = None;
+
+                if frame.fin() {
+                    self.on_message(opcode, frame.payload);
+                    None
+                } else {
+                    self.fragment_opcode = Some(opcode);
+                    self.push_fragment(&frame.payload);
+                    Some(frame.payload)
+                }
+            },
+            OpCode::Continuation => {
+                match self.fragment_opcode {
+                    Some(opcode) => {
+                        if self.push_fragment(&frame.payload) && frame.fin() {
+                            let payload = std::mem::take(&mut self.fragments);
+                            self.fragment_opcode = None;
+                            self.on_message(opcode, payload);
+                        }
+                    },
+                    None => {
+                        self.enqueue(WebSocketFrame::close(CloseCode::ProtocolError,
+                                                           "unexpected continuation frame"));
+                    }
+                }
+                Some(frame.payload)
+            },
+            OpCode::Ping => {
+                println!("ping/pong");
+                let pong = match self.on_ping {
+                    Some(ref on_ping) => {
+                        let payload = on_ping(&frame.payload);
+                        if payload.len() > MAX_CONTROL_PAYLOAD_LEN {
+                            println!("warning: pong payload of {} bytes is too large, echoing the ping", payload.len());
+                            WebSocketFrame::pong(&frame)
+                        } else {
+                            WebSocketFrame::unsolicited_pong(payload)
+                        }
+                    },
+                    None => WebSocketFrame::pong(&frame)
+                };
+                self.enqueue(pong);
+                Some(frame.payload)
+            },
+            OpCode::Pong => {
+                if self.pending_ping.as_ref().is_some_and(|(_, payload)| *payload == frame.payload) {
+                    self.pending_ping = None;
+                }
+                Some(frame.payload)
+            },
+            OpCode::ConnectionClose => {
+                self.enqueue(WebSocketFrame::close_from(&frame));
+                Some(frame.payload)
+            }
+        };
+
+        if let Some(payload) = unused_payload {
+            self.buffer_pool.borrow_mut().give(payload);
+        }
+        self.interest = Interest::WRITABLE;
+    }
+
+    fn enqueue(&mut self, frame: WebSocketFrame) {
+        if self.enqueue_deferred(frame) {
+            self.interest |= Interest::WRITABLE;
+        }
+    }
+
+    // Queues the frame without asking for a writable event; returns false if
+    // the frame was discarded because the connection is closing or, for data
+    // frames, because its queue is full.
+    fn enqueue_deferred(&mut self, frame: WebSocketFrame) -> bool {
+        if let ClientState::ClosingSent = self.state {
+            return false;
+        }
+        if frame.is_data() && self.config.max_outgoing_queue.is_some_and(|max| self.outgoing.len() >= max) {
+            println!("outgoing queue full, dropping frame");
+            return false;
+        }
+
+        if frame.is_close() {
+            let code = frame.close_code().unwrap_or(CloseCode::Normal);
+            self.close_code.get_or_insert(code);
+            self.record(ConnectionEventKind::Closed(code));
+        }
+
+        self.outgoing.push(QueuedFrame::new(frame, self.config.message_ttl));
+        true
+    }
+
+    // Queues `text` as a fragmented message. Like `enqueue`, the caller has to
+    // re-register the socket. Fragments never expire: dropping one would
+    // leave the peer with half a message.
+    pub fn send_text_fragmented(&mut self, text: &str, fragment_size: usize) {
+        if let ClientState::ClosingSent = self.state {
+            return;
+        }
+
+        for fragment in WebSocketFrame::fragment(text.as_bytes(), fragment_size, OpCode::TextFrame) {
+            self.outgoing.push(QueuedFrame::new(fragment, None));
+        }
+        self.interest |= Interest::WRITABLE;
+    }
+
+    // Splits unfragmented data frames larger than `max_frame_size`. Done right
+    // before writing, so frames queued before the limit was set (or grown by
+    // an outgoing filter) are covered too.
+    fn split_oversized(frame: WebSocketFrame, max_frame_size: Option<usize>) -> Vec<WebSocketFrame> {
+        match max_frame_size {
+            Some(max) if frame.is_data() && frame.fin() && frame.payload.len() > max => {
+                WebSocketFrame::fragment(&frame.payload, max, frame.get_opcode())
+            },
+            _ => vec![frame]
+        }
+    }
+
+    // Like `enqueue`, the caller has to re-register the socket for the new
+    // interest to take effect. If the previous ping is still unanswered the
+    // peer is considered gone and the connection is closed instead.
+    pub fn send_ping(&mut self, payload: &[u8]) -> Result<(), WebSocketError> {
+        Self::check_control_payload(payload)?;
+
+        if let Some((sent_at, _)) = self.pending_ping {
+            println!("no pong within {:?}, closing", sent_at.elapsed());
+            self.flush_and_close(CloseCode::GoingAway, "ping timeout");
+            return Ok(());
+        }
+
+        self.pending_ping = Some((Instant::now(), payload.to_vec()));
+        self.enqueue(WebSocketFrame::ping(payload.to_vec()));
+        Ok(())
+    }
+
+    pub fn send_pong(&mut self, payload: &[u8]) -> Result<(), WebSocketError> {
+        Self::check_control_payload(payload)?;
+        self.enqueue(WebSocketFrame::unsolicited_pong(payload.to_vec()));
+        Ok(())
+    }
+
+    fn check_control_payload(payload: &[u8]) -> Result<(), WebSocketError> {
+        if payload.len() > MAX_CONTROL_PAYLOAD_LEN {
+            return Err(WebSocketError::PayloadTooLarge { limit: MAX_CONTROL_PAYLOAD_LEN, actual: payload.len() });
+        }
+        Ok(())
+    }
+
+    pub fn flush_and_close(&mut self, code: CloseCode, reason: &str) {
+        self.enqueue(WebSocketFrame::close(code, reason));
+        self.state = ClientState::ClosingSent;
+    }
+
+    fn push_fragment(&mut self, payload: &[u8]) -> bool {
+        if self.fragments.len() + payload.len() > self.config.max_message_size {
+            println!("assembled message exceeds {} bytes", self.config.max_message_size);
+            self.fragments.clear();
+            self.fragment_opcode = None;
+            self.enqueue(WebSocketFrame::close(CloseCode::MessageTooBig, ""));
+            return false;
+        }
+
+        self.fragments.extend_from_slice(payload);
+        true
+    }
+
+    fn on_message(&mut self, opcode: OpCode, payload: Vec<u8>) {
+        self.record(ConnectionEventKind::MessageReceived);
+        match opcode {
+            OpCode::TextFrame => match String::from_utf8(payload) {
+                Ok(text) => self.incoming.push(Message::Text(text)),
+                Err(_) => self.enqueue(WebSocketFrame::close(CloseCode::InvalidPayload, ""))
+            },
+            _ => self.incoming.push(Message::Binary(payload))
+        }
+    }
+
+    // Once our close frame is out only the peer's hang-up matters, but anything
+    // it still sends has to be read or the socket keeps reporting readable.
+    fn discard_input(&mut self) -> Result<(), WebSocketError> {
+        let mut buf = [0; 1024];
+        loop {
+            match self.socket.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {},
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(WebSocketError::from(e))
+            }
+        }
+    }
+
+    fn read_handshake(&mut self) -> Result<(), WebSocketError> {
+        let mut buf = self.buffer_pool.borrow_mut().take();
+        buf.resize(HANDSHAKE_BUF_LEN, 0);
+        let result = self.read_handshake_into(&mut buf);
+        self.buffer_pool.borrow_mut().give(buf);
+        result
+    }
+
+    fn read_handshake_into(&mut self, buf: &mut [u8]) -> Result<(), WebSocketError> {
+        loop {
+            match self.socket.read(buf) {
+                Ok(0) => break, // Peer closed the connection.
+                Ok(len) => {
+                    let after_proxy_header;
+                    let bytes: &[u8] = if self.awaiting_proxy_header {
+                        match self.consume_proxy_header(&buf[..len]) {
+                            Ok(Some(rest)) => {
+                                after_proxy_header = rest;
+                                &after_proxy_header
+                            },
+                            Ok(None) => continue,
+                            Err(e) => return Err(e)
+                        }
+                    } else {
+                        &buf[..len]
+                    };
+
+                    self.header_bytes_read += bytes.len();
+                    if self.header_bytes_read > self.config.max_header_size {
+                        self.reject_handshake(431, "Request Header Fields Too Large");
+                        return Err(WebSocketError::InvalidHandshake(
+                            format!("request headers exceed {} bytes", self.config.max_header_size)));
+                    }
+
+                    self.request_buf.extend_from_slice(bytes);
+                    let request = loop {
+                        match http::parse(&self.request_buf) {
+                            ParseResult::Incomplete => break None,
+                            ParseResult::Complete(request) => break Some(request),
+                            ParseResult::Error(reason) if self.handshake_retries_remaining > 0 => {
+                                self.handshake_retries_remaining -= 1;
+                                println!("discarding malformed request ({}), {} retries left",
+                                         reason, self.handshake_retries_remaining);
+                                // Whatever follows the bad head may already be a whole request.
+                                let end = self.request_buf.windows(4).position(|w| w == b"\r\n\r\n")
+                                    .map_or(self.request_buf.len(), |end| end + 4);
+                                self.request_buf.drain(..end);
+                            },
+                            ParseResult::Error(reason) => {
+                                self.reject_handshake(400, "Bad Request");
+                                return Err(WebSocketError::InvalidHandshake(reason));
+                            }
+                        }
+                    };
+                    let request = match request {
+                        Some(request) => request,
+                        None => continue
+                    };
+                    self.request_buf = Vec::new();
+
+                    if request.is_upgrade() {
+                        self.request_line = Some((request.method, request.path));
+                        self.headers = request.headers;
+                        if let Some(extensions) = self.header("Sec-WebSocket-Extensions") {
+                            // The agreed parameters aren't sent back in the response:
+                            // there is no deflate implementation to apply them to, and
+                            // a client that saw the extension accepted would start
+                            // sending compressed frames.
+                            self.deflate = match deflate::negotiate(&extensions, &self.config.compression) {
+                                Ok(params) => params,
+                                Err(reason) => {
+                                    self.reject_handshake(400, "Bad Request");
+                                    return Err(WebSocketError::InvalidHandshake(reason));
+                                }
+                            };
+                        }
+                        self.state = ClientState::HandshakeResponse;
+                        self.interest = Interest::WRITABLE;
+                        break;
+                    }
+
+                    // Not a WebSocket upgrade: the server answers it over plain HTTP.
+                    self.state = ClientState::HttpRequestReceived(request);
+                    self.interest = Interest::WRITABLE;
+                    break;
+                },
+                // Socket buffer has got no more bytes.
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(WebSocketError::from(e))
+            }
+        }
+        Ok(())
+    }
+
+    // Best effort: the connection is dropped whether or not this gets through.
+    fn reject_handshake(&mut self, status: u16, reason: &str) {
+        let _ = self.socket.write(format!("HTTP/1.1 {} {}\r\n\r\n", status, reason).as_bytes());
+        self.rejected_with = Some(status);
+    }
+
+    fn consume_proxy_header(&mut self, bytes: &[u8]) -> Result<Option<Vec<u8>>, WebSocketError> {
+        self.proxy_header_buf.extend_from_slice(bytes);
+
+        match proxy_protocol::parse(&self.proxy_header_buf)? {
+            Some((len, source)) => {
+                if let Some(source) = source {
+                    self.peer_addr = source;
+                }
+                self.awaiting_proxy_header = false;
+
+                let rest = self.proxy_header_buf.split_off(len);
+                self.proxy_header_buf = Vec::new();
+                Ok(Some(rest))
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        self.headers.iter()
+            .find(|&(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    }
+
+    fn real_peer_addr(&self) -> IpAddr {
+        if self.config.trust_forwarded_headers {
+            let forwarded = self.header("X-Forwarded-For")
+                .and_then(|value| value.split(',').next().and_then(|ip| ip.trim().parse().ok()))
+                .or_else(|| self.header("X-Real-IP").and_then(|ip| ip.trim().parse().ok()));
+
+            if let Some(ip) = forwarded {
+                return ip;
+            }
+        }
+
+        self.peer_addr.ip()
+    }
+
+    // A client that keeps failing is polled again only after an exponentially
+    // growing delay, so it can't spin the event loop.
+    fn error_backoff_ms(&self) -> Option<u64> {
+        if self.consecutive_errors <= ERROR_BACKOFF_THRESHOLD {
+            return None;
+        }
+        let shift = cmp::min(self.consecutive_errors - ERROR_BACKOFF_THRESHOLD - 1, ERROR_BACKOFF_MAX_SHIFT);
+        Some(ERROR_BACKOFF_BASE_MS << shift)
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            ClientState::AwaitingHandshake => "awaiting_handshake",
+            ClientState::HandshakeResponse => "handshake_response",
+            ClientState::HttpRequestReceived(_) => "http_request",
+            ClientState::HttpResponse => "http_response",
+            ClientState::Connected => "connected",
+            ClientState::ClosingSent => "closing"
+        }
+    }
+
+    fn is_awaiting_handshake(&self) -> bool {
+        matches!(self.state, ClientState::AwaitingHandshake)
+    }
+
+    fn is_connected(&self) -> bool {
+        matches!(self.state, ClientState::Connected)
+    }
+
+    fn is_closing(&self) -> bool {
+        matches!(self.state, ClientState::ClosingSent)
+    }
+
+    fn write(&mut self, filters: &[Box<dyn FrameFilter>]) -> Result<(), WebSocketError> {
+        match self.state {
+            ClientState::HandshakeResponse => self.write_handshake()?,
+            ClientState::HttpResponse => self.write_http_response()?,
+            ClientState::Connected | ClientState::ClosingSent => {
+                // Frames stay queued, where the TTL and send deadline apply,
+                // until the socket has taken everything encoded before them.
+                if self.flush_write_buf()? {
+                    self.encode_outgoing(filters)?;
+                    self.flush_write_buf()?;
+                }
+                self.interest = if self.write_buf.is_empty() {
+                    Interest::READABLE
+                } else {
+                    Interest::READABLE | Interest::WRITABLE
+                };
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Serializes the queued frames into `write_buf`.
+    fn encode_outgoing(&mut self, filters: &[Box<dyn FrameFilter>]) -> Result<(), WebSocketError> {
+        println!("sending {} frames ({})", self.outgoing.len(), self.state_name());
+
+        let mut close_connection = false;
+        let now = Instant::now();
+
+        let mut outgoing = std::mem::take(&mut self.outgoing).into_iter();
+        while let Some(queued) = outgoing.next() {
+            // After a close frame nothing else goes out; what's left is
+            // dead-lettered when the client disconnects.
+            if close_connection {
+                self.outgoing.push(queued);
+                self.outgoing.extend(outgoing);
+                break;
+            }
+
+            if queued.is_expired(now) {
+                println!("warning: dropping frame that expired after {:?}", now.duration_since(queued.enqueued_at));
+                self.expired.push(queued.frame);
+                continue;
+            }
+
+            if let Some(deadline) = self.config.send_deadline {
+                let waited = now.duration_since(queued.enqueued_at);
+                if waited > deadline {
+                    println!("warning: dropping frame that waited {:?} to be sent", waited);
+                    self.dropped_frames += 1;
+                    self.expired.push(queued.frame);
+                    continue;
+                }
+            }
+
+            let frame = match filter::apply_outgoing(filters, queued.frame) {
+                Some(frame) => frame,
+                None => continue
+            };
+
+            for frame in Self::split_oversized(frame, self.config.max_frame_size) {
+                #[cfg(feature = "sequence")]
+                let frame = match self.send_sequence {
+                    Some(ref mut sequence) => sequence.stamp(frame),
+                    None => frame
+                };
+
+                frame.write(&mut self.write_buf)?;
+                self.send_stats.record(frame.payload.len());
+
+                if frame.is_close() {
+                    close_connection = true;
+                } else if frame.is_data() {
+                    event_log::record(&mut self.events, ConnectionEventKind::MessageSent);
+                }
+            }
+        }
+
+        if self.config.send_deadline.is_some() && self.dropped_frames > self.config.max_dropped_frames && !close_connection {
+            println!("closing connection after dropping {} frames", self.dropped_frames);
+            let close_frame = WebSocketFrame::close(CloseCode::PolicyViolation, "too many dropped frames");
+            close_frame.write(&mut self.write_buf)?;
+            self.send_stats.record(close_frame.payload.len());
+            self.close_code.get_or_insert(CloseCode::PolicyViolation);
+            self.record(ConnectionEventKind::Closed(CloseCode::PolicyViolation));
+            close_connection = true;
+        }
+
+        // The connection is dropped once the peer hangs up.
+        if close_connection {
+            self.state = ClientState::ClosingSent;
+        }
+        Ok(())
+    }
+
+    // Writes as much of `write_buf` as the socket takes. Returns true once
+    // it is empty.
+    fn flush_write_buf(&mut self) -> Result<bool, WebSocketError> {
+        while !self.write_buf.is_empty() {
+            match self.socket.write(&self.write_buf) {
+                Ok(len) => { self.write_buf.drain(..len); },
+                // Wait for the next writable event to send the rest.
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(WebSocketError::from(e))
+            }
+        }
+        Ok(true)
+    }
+
+    fn write_http_response(&mut self) -> Result<(), WebSocketError> {
+        self.flush_write_buf()?;
+        Ok(())
+    }
+
+    fn write_handshake(&mut self) -> Result<(), WebSocketError> {
+        if self.write_buf.is_empty() {
+            let key = match self.header("Sec-WebSocket-Key") {
+                Some(key) => key,
+                None => return Err(WebSocketError::InvalidHandshake("missing Sec-WebSocket-Key".to_string()))
+            };
+            let mut response = handshake::accept_response(&key);
+            // Extra headers go in ahead of the blank line that ends the response.
+            if let Some(ref subprotocol) = self.subprotocol {
+                let end = response.len() - 2;
+                response.insert_str(end, &format!("Sec-WebSocket-Protocol: {}\r\n", subprotocol));
+            }
+            if let Some(ref resume_token) = self.resume_token {
+                let end = response.len() - 2;
+                response.insert_str(end, &format!("{}: {}\r\n", RESUME_TOKEN_HEADER, resume_token));
+            }
+            self.write_buf.extend_from_slice(response.as_bytes());
+        }
+
+        if !self.flush_write_buf()? {
+            return Ok(());
+        }
+
+        self.state = ClientState::Connected;
+        self.record(ConnectionEventKind::Opened);
+        // Frames replayed into a resumed session are already waiting.
+        self.interest = if self.outgoing.is_empty() {
+            Interest::READABLE
+        } else {
+            Interest::READABLE | Interest::WRITABLE
+        };
+        Ok(())
+    }
+}
+
+enum TimerEvent {
+    SchedulerTick,
+    HandshakeTimeout(Token),
+    Reregister(Token),
+    CoalesceFlush(Token),
+    SessionExpiry(String),
+    DrainDeadline
+}
+
+pub enum ServerCommand {
+    PauseAccepting,
+    ResumeAccepting,
+    Deliver(Token, Vec<Message>),
+    Dispatched(Token),
+    SendTo(Token, WebSocketFrame),
+    Broadcast(WebSocketFrame),
+    BroadcastRoom(String, WebSocketFrame),
+    Drain,
+    Shutdown
+}
+
+// Hands commands to the event loop from other threads and wakes it up.
+#[derive(Clone)]
+pub struct CommandSender {
+    sender: mpsc::Sender<ServerCommand>,
+    waker: Arc<Waker>
+}
+
+impl CommandSender {
+    pub fn send(&self, command: ServerCommand) -> io::Result<()> {
+        if self.sender.send(command).is_err() {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "the event loop has shut down"));
+        }
+        self.waker.wake()
+    }
+}
+
+fn bind_listener(address: &SocketAddr, only_v6: Option<bool>, backlog: i32) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(*address), Type::STREAM, None)?;
+
+    if let (&SocketAddr::V6(_), Some(only_v6)) = (address, only_v6) {
+        socket.set_only_v6(only_v6)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.bind(&(*address).into())?;
+    socket.listen(backlog)?;
+    socket.set_nonblocking(true)?;
+
+    let listener: net::TcpListener = socket.into();
+    Ok(TcpListener::from_std(listener))
+}
+
+pub type ConnectCallback = Box<dyn Fn(Token, &WebSocketClient) + Send>;
+pub type DisconnectCallback = Box<dyn Fn(Token, Option<CloseCode>) + Send>;
+type PingHandler = Arc<dyn Fn(Token, &[u8]) -> Vec<u8> + Send + Sync>;
+// A `PingHandler` bound to one client's token.
+type ClientPingHandler = Box<dyn Fn(&[u8]) -> Vec<u8>>;
+
+pub struct WebSocketServer {
+    poll: Poll,
+    timers: Timers<TimerEvent>,
+    commands: Receiver<ServerCommand>,
+    running: bool,
+    listeners: Vec<(Token, TcpListener)>,
+    bind_address: SocketAddr,
+    only_v6: Option<bool>,
+    accepting: bool,
+    clients: HashMap<Token, WebSocketClient>,
+    rooms: HashMap<String, HashSet<Token>>,
+    client_rooms: HashMap<Token, HashSet<String>>,
+    room_patterns: HashMap<String, HashSet<Token>>,
+    dead_letters: VecDeque<(Token, WebSocketFrame, DeadLetterReason)>,
+    sessions: HashMap<String, Session>,
+    upstreams: HashMap<Token, Upstream>,
+    upstream_proxy: Option<(SocketAddr, Option<(String, String)>)>,
+    upstream_breaker: Option<CircuitBreaker>,
+    #[cfg(feature = "cluster")]
+    cluster_enabled: bool,
+    #[cfg(feature = "cluster")]
+    cluster_peers: HashMap<Token, Upstream>,
+    #[cfg(feature = "cluster")]
+    seen_ids: SeenMessages,
+    proxies: HashMap<Token, Token>,
+    on_error: Box<dyn Fn(Token, WebSocketError) + Send>,
+    on_connect: Option<ConnectCallback>,
+    on_disconnect: Option<DisconnectCallback>,
+    on_ping: Option<PingHandler>,
+    access_log: Option<Box<dyn Write + Send>>,
+    scheduler: Scheduler,
+    buffer_pool: Rc<RefCell<BufferPool>>,
+    closed_metrics: Metrics,
+    connections_total: u64,
+    started_at: Instant,
+    command_sender: CommandSender,
+    tick_count: u64,
+    shutting_down: bool,
+    token_counter: usize,
+    filters: Vec<Box<dyn FrameFilter>>,
+    handler: Arc<dyn ConnectionHandler>,
+    handler_factory: Option<Box<dyn Fn() -> Box<dyn ConnectionHandler> + Send>>,
+    subprotocol_handlers: HashMap<String, Arc<dyn ConnectionHandler>>,
+    thread_pool: rayon::ThreadPool,
+    config: Arc<ServerConfig>
+}
+
+impl Default for WebSocketServer {
+    fn default() -> WebSocketServer {
+        WebSocketServer::new()
+    }
+}
+
+impl WebSocketServer {
+    pub fn new() -> WebSocketServer {
+        let poll = Poll::new().unwrap();
+        let waker = Waker::new(poll.registry(), WAKER_TOKEN).unwrap();
+        let (sender, commands) = mpsc::channel();
+
+        WebSocketServer {
+            poll: poll,
+            timers: Timers::new(),
+            commands: commands,
+            running: false,
+            listeners: Vec::new(),
+            bind_address: "127.0.0.1:10000".parse().unwrap(),
+            only_v6: None,
+            accepting: false,
+            clients: HashMap::new(),
+            rooms: HashMap::new(),
+            client_rooms: HashMap::new(),
+            room_patterns: HashMap::new(),
+            dead_letters: VecDeque::new(),
+            sessions: HashMap::new(),
+            upstreams: HashMap::new(),
+            upstream_proxy: None,
+            upstream_breaker: None,
+            #[cfg(feature = "cluster")]
+            cluster_enabled: false,
+            #[cfg(feature = "cluster")]
+            cluster_peers: HashMap::new(),
+            #[cfg(feature = "cluster")]
+            seen_ids: SeenMessages::new(SEEN_IDS_CAPACITY),
+            proxies: HashMap::new(),
+            on_error: Box::new(|token, e| println!("error on {:?}: {}", token, e)),
+            on_connect: None,
+            on_disconnect: None,
+            on_ping: None,
+            access_log: None,
+            scheduler: Scheduler::new(),
+            buffer_pool: Rc::new(RefCell::new(BufferPool::new(BUFFER_POOL_SIZE))),
+            closed_metrics: Metrics::default(),
+            connections_total: 0,
+            started_at: Instant::now(),
+            command_sender: CommandSender { sender: sender, waker: Arc::new(waker) },
+            tick_count: 0,
+            shutting_down: false,
+            token_counter: 1,
+            filters: Vec::new(),
+            handler: Arc::new(GreetingHandler),
+            handler_factory: None,
+            subprotocol_handlers: HashMap::new(),
+            thread_pool: rayon::ThreadPoolBuilder::new().build().unwrap(),
+            config: Arc::new(ServerConfig::default())
+        }
+    }
+
+    // Serves the listening sockets passed in by systemd socket activation
+    // (`LISTEN_PID` and `LISTEN_FDS`), which start at file descriptor 3. Fails
+    // with `NotFound` if the process wasn't started that way.
+    #[cfg(unix)]
+    pub fn from_systemd() -> io::Result<WebSocketServer> {
+        use std::os::unix::io::FromRawFd;
+
+        let not_activated = || io::Error::new(ErrorKind::NotFound, "no sockets passed by systemd");
+        let listen_pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+        if listen_pid != Some(process::id()) {
+            return Err(not_activated());
+        }
+        let count = env::var("LISTEN_FDS").ok()
+            .and_then(|fds| fds.parse::<i32>().ok())
+            .filter(|&count| count > 0)
+            .ok_or_else(not_activated)?;
+
+        let mut sockets = Vec::new();
+        for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+            // systemd hands these descriptors over to us, so we own them.
+            let socket = unsafe { net::TcpListener::from_raw_fd(fd) };
+            socket.set_nonblocking(true)?;
+            sockets.push(TcpListener::from_std(socket));
+        }
+
+        let mut server = WebSocketServer::new();
+        server.add_listeners(sockets)?;
+        Ok(server)
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    // Clients keep the configuration they were accepted with; changes made
+    // through here only reach clients accepted afterwards.
+    fn config_mut(&mut self) -> &mut ServerConfig {
+        Arc::make_mut(&mut self.config)
+    }
+
+    // Replaces the configuration while the server is running, including for
+    // connected clients. Most limits apply right away: payload, message, frame
+    // and header sizes, outgoing queue length, send deadline, message TTL,
+    // read, idle and pong timeouts, ping interval, coalescing, session TTL,
+    // forwarded headers, and compression for handshakes still in progress.
+    // What is set up when a connection is accepted only changes for later
+    // connections: connection limits, nodelay, rate limits, PROXY protocol,
+    // sequence numbers, handshake timeout and retries. The backlog only
+    // applies to listeners bound afterwards.
+    pub fn reload_config(&mut self, config: ServerConfig) {
+        let config = Arc::new(config);
+        self.config = config.clone();
+        self.for_each_client_mut(|_, client| {
+            client.read_buf.set_max_payload_len(config.max_payload_len);
+            client.config = config.clone();
+        });
+    }
+
+    pub fn with_bind_address(mut self, bind_address: SocketAddr) -> WebSocketServer {
+        self.bind_address = bind_address;
+        self
+    }
+
+    /// Controls `IPV6_V6ONLY` when binding an IPv6 address. When left unset the
+    /// platform default applies: Linux accepts IPv4-mapped connections on `[::]`
+    /// (dual-stack), while Windows and most BSDs, including macOS, only accept
+    /// IPv6. Pass `false` to request dual-stack explicitly.
+    pub fn with_only_v6(mut self, only_v6: bool) -> WebSocketServer {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    // Length of the kernel's pending connection queue. Raise it if clients see
+    // refused connections during bursts of connects.
+    pub fn with_backlog(mut self, backlog: i32) -> WebSocketServer {
+        self.config_mut().backlog = backlog;
+        self
+    }
+
+    // Reaches relay upstreams through an HTTP proxy with `CONNECT`. The tunnel
+    // is set up synchronously, so the event loop waits on the proxy meanwhile.
+    pub fn with_upstream_proxy(mut self, proxy_addr: SocketAddr, credentials: Option<(String, String)>) -> WebSocketServer {
+        self.upstream_proxy = Some((proxy_addr, credentials));
+        self
+    }
+
+    // After `threshold` upstream connects in a row fail (including failed
+    // upgrades), `proxy` refuses to connect for `retry_delay_ms`.
+    pub fn with_upstream_circuit_breaker(mut self, threshold: u32, retry_delay_ms: u64) -> WebSocketServer {
+        self.upstream_breaker = Some(CircuitBreaker::new(threshold, Duration::from_millis(retry_delay_ms)));
+        self
+    }
+
+    pub fn with_proxy_protocol(mut self, proxy_protocol: bool) -> WebSocketServer {
+        self.config_mut().proxy_protocol = proxy_protocol;
+        self
+    }
+
+    // Only enable this when every connection arrives through a trusted reverse
+    // proxy, otherwise clients can spoof their address with these headers.
+    pub fn with_trust_forwarded_headers(mut self, trust: bool) -> WebSocketServer {
+        self.config_mut().trust_forwarded_headers = trust;
+        self
+    }
+
+    // The callback only observes the error; the server closes the client
+    // afterwards regardless of what the callback does.
+    pub fn with_on_error<F>(mut self, on_error: F) -> WebSocketServer
+        where F: Fn(Token, WebSocketError) + Send + 'static
+    {
+        self.on_error = Box::new(on_error);
+        self
+    }
+
+    // Called once the handshake response has been written and the client can
+    // start exchanging frames.
+    pub fn with_on_connect<F>(mut self, on_connect: F) -> WebSocketServer
+        where F: Fn(Token, &WebSocketClient) + Send + 'static
+    {
+        self.on_connect = Some(Box::new(on_connect));
+        self
+    }
+
+    // Called for every client `on_connect` was called for, just before its
+    // socket is closed. The code is `None` if the connection went away without
+    // a close frame being exchanged.
+    pub fn with_on_disconnect<F>(mut self, on_disconnect: F) -> WebSocketServer
+        where F: Fn(Token, Option<CloseCode>) + Send + 'static
+    {
+        self.on_disconnect = Some(Box::new(on_disconnect));
+        self
+    }
+
+    // Replaces the echoed ping payload in pongs, e.g. to add a server
+    // timestamp for latency measurements. Pongs are limited to 125 bytes;
+    // longer payloads are ignored and the ping is echoed instead.
+    pub fn with_ping_handler<F>(mut self, handler: F) -> WebSocketServer
+        where F: Fn(Token, &[u8]) -> Vec<u8> + Send + Sync + 'static
+    {
+        self.on_ping = Some(Arc::new(handler));
+        self
+    }
+
+    // Writes a line in Apache Combined Log Format for every completed
+    // handshake, rejected handshake and plain HTTP response.
+    pub fn with_access_log<W: Write + Send + 'static>(mut self, writer: W) -> WebSocketServer {
+        self.access_log = Some(Box::new(writer));
+        self
+    }
+
+    pub fn with_handler<H: ConnectionHandler>(mut self, handler: H) -> WebSocketServer {
+        self.handler = Arc::new(handler);
+        self
+    }
+
+    // Builds a fresh handler for every accepted connection instead of sharing
+    // the one set by `with_handler`.
+    pub fn with_handler_factory<F, H>(mut self, factory: F) -> WebSocketServer
+        where F: Fn() -> H + Send + 'static, H: ConnectionHandler
+    {
+        self.handler_factory = Some(Box::new(move || Box::new(factory()) as Box<dyn ConnectionHandler>));
+        self
+    }
+
+    // Clients offering `protocol` in `Sec-WebSocket-Protocol` are served by
+    // `handler`. The first offered protocol with a handler wins; clients that
+    // don't agree on one get the default handler.
+    pub fn with_subprotocol_handler<H: ConnectionHandler>(mut self, protocol: &str, handler: H) -> WebSocketServer {
+        self.subprotocol_handlers.insert(protocol.to_string(), Arc::new(handler));
+        self
+    }
+
+    pub fn with_send_deadline(mut self, send_deadline_ms: u64, max_dropped_frames: usize) -> WebSocketServer {
+        let config = self.config_mut();
+        config.send_deadline = Some(Duration::from_millis(send_deadline_ms));
+        config.max_dropped_frames = max_dropped_frames;
+        self
+    }
+
+    // Data frames still queued this long after being sent are dropped instead
+    // of written. Unlike the send deadline, expired frames never close the
+    // connection.
+    pub fn with_message_ttl(mut self, message_ttl_ms: u64) -> WebSocketServer {
+        self.config_mut().message_ttl = Some(Duration::from_millis(message_ttl_ms));
+        self
+    }
+
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> WebSocketServer {
+        self.config_mut().max_frame_size = Some(max_frame_size);
+        self
+    }
+
+    // Like `with_max_frame_size`, but also applies to clients that are
+    // already connected, including the frames they have queued.
+    pub fn set_max_frame_size(&mut self, bytes: usize) {
+        self.config_mut().max_frame_size = Some(bytes);
+        let config = self.config.clone();
+        self.for_each_client_mut(|_, client| client.config = config.clone());
+    }
+
+    pub fn with_max_payload_len(mut self, max_payload_len: usize) -> WebSocketServer {
+        self.config_mut().max_payload_len = max_payload_len;
+        self
+    }
+
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> WebSocketServer {
+        self.config_mut().max_message_size = max_message_size;
+        self
+    }
+
+    pub fn with_handshake_timeout(mut self, handshake_timeout_ms: u64) -> WebSocketServer {
+        self.config_mut().handshake_timeout_ms = handshake_timeout_ms;
+        self
+    }
+
+    // Connections that leave a frame half-delivered for longer than this are
+    // closed. Checked once per scheduler tick, so it can take up to a second
+    // longer.
+    pub fn with_read_timeout(mut self, read_timeout_ms: u64) -> WebSocketServer {
+        self.config_mut().read_timeout = Some(Duration::from_millis(read_timeout_ms));
+        self
+    }
+
+    // Closes connections that send nothing for `idle_timeout_ms`. Like the read
+    // timeout it is checked on each pass through the event loop.
+    pub fn with_idle_timeout(mut self, idle_timeout_ms: u64) -> WebSocketServer {
+        self.config_mut().idle_timeout_ms = Some(idle_timeout_ms);
+        self
+    }
+
+    // Pings connections that have been quiet for `ping_interval_ms` and closes
+    // them if the pong takes longer than `pong_timeout_ms`.
+    pub fn with_ping_interval(mut self, ping_interval_ms: u64, pong_timeout_ms: u64) -> WebSocketServer {
+        let config = self.config_mut();
+        config.ping_interval_ms = Some(ping_interval_ms);
+        config.pong_timeout_ms = Some(pong_timeout_ms);
+        self
+    }
+
+    // Connections over either limit are dropped as soon as they are accepted.
+    pub fn with_max_connections(mut self, max_connections: usize) -> WebSocketServer {
+        self.config_mut().max_connections = Some(max_connections);
+        self
+    }
+
+    pub fn with_max_connections_per_ip(mut self, max_connections_per_ip: usize) -> WebSocketServer {
+        self.config_mut().max_connections_per_ip = Some(max_connections_per_ip);
+        self
+    }
+
+    // Data frames queued for a client beyond this many are dropped.
+    pub fn with_max_outgoing_queue(mut self, max_outgoing_queue: usize) -> WebSocketServer {
+        self.config_mut().max_outgoing_queue = Some(max_outgoing_queue);
+        self
+    }
+
+    pub fn with_nodelay(mut self, nodelay: bool) -> WebSocketServer {
+        self.config_mut().nodelay = nodelay;
+        self
+    }
+
+    pub fn with_max_header_size(mut self, max_header_size: usize) -> WebSocketServer {
+        self.config_mut().max_header_size = max_header_size;
+        self
+    }
+
+    // Malformed request heads to skip before giving up on a connection, for
+    // peers that send some junk (e.g. stray TLS bytes) ahead of the request.
+    pub fn with_max_handshake_retries(mut self, max_handshake_retries: u8) -> WebSocketServer {
+        self.config_mut().max_handshake_retries = max_handshake_retries;
+        self
+    }
+
+    // Holds outgoing data frames for up to `coalesce_window_ms` so bursts of
+    // small messages go out in one write. Control frames are never delayed.
+    pub fn with_coalesce_window(mut self, coalesce_window_ms: u64) -> WebSocketServer {
+        self.config_mut().coalesce_window_ms = coalesce_window_ms;
+        self
+    }
+
+    // Hands every client a resume token in the handshake response. A client
+    // reconnecting within `session_ttl_ms` with that token in its upgrade
+    // request gets its rooms back, along with frames that were still queued
+    // when it dropped.
+    pub fn with_session_resumption(mut self, session_ttl_ms: u64) -> WebSocketServer {
+        self.config_mut().session_ttl_ms = Some(session_ttl_ms);
+        self
+    }
+
+    // Stamps outgoing data frames with a sequence number (RSV2 plus an 8-byte
+    // suffix) and strips them from incoming ones. Nothing negotiates this, so
+    // a standard client will fail the connection on the first stamped frame.
+    #[cfg(feature = "sequence")]
+    pub fn with_sequence_numbers(mut self, sequence_numbers: bool) -> WebSocketServer {
+        self.config_mut().sequence_numbers = sequence_numbers;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: CompressionConfig) -> WebSocketServer {
+        self.config_mut().compression = compression;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate: f64, burst: u32) -> WebSocketServer {
+        self.config_mut().rate_limit = Some((rate, burst));
+        self
+    }
+
+    pub fn listen(&mut self) -> io::Result<()> {
+        self.bind_multiple(vec![self.bind_address])
+    }
+
+    // Listens on every address, e.g. one port for `ws://` and another for
+    // `wss://`. Listener tokens count down from just below the waker's so they
+    // never collide with client tokens.
+    pub fn bind_multiple(&mut self, addrs: Vec<SocketAddr>) -> io::Result<()> {
+        let mut sockets = Vec::new();
+        for address in addrs {
+            sockets.push(bind_listener(&address, self.only_v6, self.config.backlog)?);
+        }
+        self.add_listeners(sockets)
+    }
+
+    fn add_listeners(&mut self, sockets: Vec<TcpListener>) -> io::Result<()> {
+        let first = self.listeners.is_empty();
+        for mut socket in sockets {
+            let token = Token(WAKER_TOKEN.0 - 1 - self.listeners.len());
+            self.poll.registry().register(&mut socket, token, Interest::READABLE)?;
+            self.listeners.push((token, socket));
+        }
+        self.accepting = true;
+        if first {
+            self.timers.timeout_ms(TimerEvent::SchedulerTick, SCHEDULER_INTERVAL_MS);
+        }
+        Ok(())
+    }
+
+    fn is_listener(&self, token: Token) -> bool {
+        self.listeners.iter().any(|&(listener_token, _)| listener_token == token)
+    }
+
+    // Lets other threads push commands (broadcasts, sends, shutdown) into the
+    // event loop without blocking it. Commands sent before `run` wait in the queue.
+    pub fn command_sender(&self) -> CommandSender {
+        self.command_sender.clone()
+    }
+
+    pub fn pause_accepting(&mut self) {
+        if !self.accepting {
+            return;
+        }
+
+        for (_, socket) in self.listeners.iter_mut() {
+            match self.poll.registry().deregister(socket) {
+                Ok(()) => self.accepting = false,
+                Err(e) => println!("failed to pause accepting: {}", e)
+            }
+        }
+    }
+
+    pub fn resume_accepting(&mut self) {
+        if self.accepting {
+            return;
+        }
+
+        for &mut (token, ref mut socket) in self.listeners.iter_mut() {
+            match self.poll.registry().register(socket, token, Interest::READABLE) {
+                Ok(()) => self.accepting = true,
+                Err(e) => println!("failed to resume accepting: {}", e)
+            }
+        }
+    }
+
+    fn dispatch_messages(&mut self, token: Token) {
+        #[cfg(feature = "cluster")]
+        if self.clients.get(&token).is_some_and(|client| client.subprotocol.as_deref() == Some(cluster::PROTOCOL)) {
+            self.receive_cluster_messages(token);
+            return;
+        }
+
+        // One job per client at a time, so its messages are handled in the
+        // order they arrived; the rest are picked up once it's `Dispatched`.
+        let (messages, handler) = match self.clients.get_mut(&token) {
+            Some(client) if !client.dispatching && !client.incoming.is_empty() => {
+                client.dispatching = true;
+                (std::mem::take(&mut client.incoming),
+                 client.handler.clone().unwrap_or_else(|| self.handler.clone()))
+            },
+            _ => return
+        };
+        let sender = self.command_sender.clone();
+
+        self.thread_pool.spawn(move || {
+            for message in messages {
+                let replies = handler.on_message(token, message);
+                if !replies.is_empty() {
+                    if let Err(e) = sender.send(ServerCommand::Deliver(token, replies)) {
+                        println!("failed to deliver replies: {:?}", e);
+                    }
+                }
+            }
+            if let Err(e) = sender.send(ServerCommand::Dispatched(token)) {
+                println!("failed to finish dispatching: {:?}", e);
+            }
+        });
+    }
+
+    fn finish_dispatch(&mut self, token: Token) {
+        if let Some(client) = self.clients.get_mut(&token) {
+            client.dispatching = false;
+        }
+        self.dispatch_messages(token);
+    }
+
+    fn respond_http(&mut self, token: Token) {
+        let client = match self.clients.get_mut(&token) {
+            Some(client) => client,
+            None => return
+        };
+        let request = match std::mem::replace(&mut client.state, ClientState::HttpResponse) {
+            ClientState::HttpRequestReceived(request) => request,
+            state => {
+                client.state = state;
+                return;
+            }
+        };
+
+        // The health endpoint is answered by the server itself.
+        let response = if request.method == "GET" && request.path == HEALTH_PATH {
+            http_fallback::response(200, "application/json", self.stats_snapshot().to_json().as_bytes())
+        } else {
+            let handler = match client.handler {
+                Some(ref handler) => handler.clone(),
+                None => self.handler.clone()
+            };
+            HttpFallbackHandler::new(handler).respond(&request)
+        };
+        let (status, body_len) = access_log::response_summary(&response);
+        self.log_access(token, Some(&request), status, body_len);
+        let client = self.clients.get_mut(&token).unwrap();
+        println!("{} {} over plain HTTP", request.method, request.path);
+
+        // The connection is closed once the whole response has been written.
+        client.write_buf = response;
+        client.draining = true;
+        client.interest = Interest::WRITABLE;
+        if let Err(e) = client.reregister(self.poll.registry(), token) {
+            (self.on_error)(token, WebSocketError::from(e));
+            self.disconnect(token);
+        }
+    }
+
+    // `request` is the plain HTTP request being answered; for handshakes the
+    // request line and headers are taken from the client.
+    fn log_access(&mut self, token: Token, request: Option<&HttpRequest>, status: u16, bytes: usize) {
+        let (log, client) = match (self.access_log.as_mut(), self.clients.get(&token)) {
+            (Some(log), Some(client)) => (log, client),
+            _ => return
+        };
+        let header = |name: &str| match request {
+            Some(request) => request.header(name).cloned(),
+            None => client.header(name)
+        };
+        let origin = header("Origin");
+        let user_agent = header("User-Agent");
+
+        let entry = AccessLogEntry {
+            ip: client.real_peer_addr(),
+            at: SystemTime::now(),
+            request: match request {
+                Some(request) => Some((&request.method, &request.path)),
+                None => client.request_line.as_ref().map(|(method, path)| (method.as_str(), path.as_str()))
+            },
+            status: status,
+            bytes: bytes,
+            origin: origin.as_deref(),
+            user_agent: user_agent.as_deref()
+        };
+        if let Err(e) = writeln!(log, "{}", entry) {
+            println!("failed to write the access log: {}", e);
+        }
+    }
+
+    fn deliver(&mut self, token: Token, messages: Vec<Message>) {
+        for message in messages {
+            if self.send_to(token, message.into_frame()).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn send_to(&mut self, token: Token,
+               frame: WebSocketFrame) -> Result<(), WebSocketError> {
+        let client = match self.clients.get_mut(&token) {
+            Some(client) => client,
+            None => return Err(WebSocketError::ClientNotFound)
+        };
+
+        if self.config.coalesce_window_ms > 0 && frame.is_data() {
+            if client.enqueue_deferred(frame) && !client.flush_scheduled {
+                self.timers.timeout_ms(TimerEvent::CoalesceFlush(token), self.config.coalesce_window_ms);
+                client.flush_scheduled = true;
+            }
+        } else {
+            client.enqueue(frame);
+        }
+
+        client.reregister(self.poll.registry(), token)?;
+        Ok(())
+    }
+
+    // Stops reading from the client until `resume_client`, e.g. while the
+    // application catches up on its messages. Frames are still sent.
+    pub fn pause_client(&mut self, token: Token) -> Result<(), WebSocketError> {
+        self.set_paused(token, true)
+    }
+
+    pub fn resume_client(&mut self, token: Token) -> Result<(), WebSocketError> {
+        self.set_paused(token, false)
+    }
+
+    fn set_paused(&mut self, token: Token, paused: bool) -> Result<(), WebSocketError> {
+        let client = match self.clients.get_mut(&token) {
+            Some(client) => client,
+            None => return Err(WebSocketError::ClientNotFound)
+        };
+        client.paused = paused;
+        client.reregister(self.poll.registry(), token)?;
+        Ok(())
+    }
+
+    // The headers the client sent with its handshake, or `None` if there's no
+    // such client or it hasn't finished the handshake.
+    pub fn get_client_headers(&self, token: Token) -> Option<HashMap<String, String>> {
+        match self.clients.get(&token) {
+            Some(client) if matches!(client.state, ClientState::Connected | ClientState::ClosingSent) =>
+                Some(client.headers.clone()),
+            _ => None
+        }
+    }
+
+    fn flush_coalesced(&mut self, token: Token) {
+        if let Some(client) = self.clients.get_mut(&token) {
+            client.flush_scheduled = false;
+            if client.outgoing.is_empty() {
+                return;
+            }
+
+            client.interest |= Interest::WRITABLE;
+            if let Err(e) = client.reregister(self.poll.registry(), token) {
+                println!("failed to flush coalesced frames for {:?}: {}", token, e);
+            }
+        }
+    }
+
+    pub fn send_text(&mut self, token: Token, text: &str) -> Result<(), WebSocketError> {
+        self.send_to(token, WebSocketFrame::from(text))
+    }
+
+    pub fn send_binary(&mut self, token: Token, data: Vec<u8>) -> Result<(), WebSocketError> {
+        self.send_to(token, WebSocketFrame::binary(data))
+    }
+
+    pub fn broadcast(&mut self, frame: WebSocketFrame) {
+        let tokens: Vec<Token> = self.clients.iter()
+            .filter(|&(_, client)| client.is_connected())
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in tokens {
+            if let Err(e) = self.send_to(token, frame.clone()) {
+                println!("broadcast to {:?} failed: {}", token, e);
+            }
+        }
+    }
+
+    // Reaches the room's members as well as everyone subscribed to a pattern
+    // matching its name; a client matching both only gets the frame once.
+    pub fn broadcast_room(&mut self, room: &str, frame: WebSocketFrame) {
+        let mut tokens: HashSet<Token> = match self.rooms.get(room) {
+            Some(members) => members.clone(),
+            None => HashSet::new()
+        };
+        for (pattern, subscribers) in &self.room_patterns {
+            if topic::matches(pattern, room) {
+                tokens.extend(subscribers);
+            }
+        }
+
+        for token in tokens {
+            if let Err(e) = self.send_to(token, frame.clone()) {
+                println!("broadcast to {:?} in room {} failed: {}", token, room, e);
+            }
+        }
+    }
+
+    // `client_rooms` is the inverse of `rooms` and has to be kept in step with it.
+    pub fn join_room(&mut self, token: Token, room: &str) {
+        self.rooms.entry(room.to_string()).or_default().insert(token);
+        self.client_rooms.entry(token).or_default().insert(room.to_string());
+    }
+
+    pub fn leave_room(&mut self, token: Token, room: &str) {
+        let now_empty = match self.rooms.get_mut(room) {
+            Some(members) => {
+                members.remove(&token);
+                members.is_empty()
+            },
+            None => false
+        };
+
+        if now_empty {
+            self.rooms.remove(room);
+        }
+
+        let no_rooms_left = match self.client_rooms.get_mut(&token) {
+            Some(rooms) => {
+                rooms.remove(room);
+                rooms.is_empty()
+            },
+            None => false
+        };
+
+        if no_rooms_left {
+            self.client_rooms.remove(&token);
+        }
+    }
+
+    pub fn rename_room(&mut self, old: &str, new: &str) -> Result<(), WebSocketError> {
+        if self.rooms.contains_key(new) {
+            return Err(WebSocketError::InvalidRoom(format!("{} already exists", new)));
+        }
+        let members = match self.rooms.remove(old) {
+            Some(members) => members,
+            None => return Err(WebSocketError::InvalidRoom(format!("{} does not exist", old)))
+        };
+
+        for token in &members {
+            if let Some(rooms) = self.client_rooms.get_mut(token) {
+                rooms.remove(old);
+                rooms.insert(new.to_string());
+            }
+        }
+        self.rooms.insert(new.to_string(), members);
+
+        let mut notice = vec![ROOM_RENAMED_TAG];
+        notice.extend_from_slice(old.as_bytes());
+        notice.push(0);
+        notice.extend_from_slice(new.as_bytes());
+        self.broadcast_room(new, WebSocketFrame::binary(notice));
+        Ok(())
+    }
+
+    // Subscribes to every room whose name matches a glob like `sports/*`,
+    // including rooms that don't exist yet.
+    pub fn subscribe_pattern(&mut self, token: Token, pattern: &str) {
+        self.room_patterns.entry(pattern.to_string()).or_default().insert(token);
+    }
+
+    pub fn unsubscribe_pattern(&mut self, token: Token, pattern: &str) {
+        let now_empty = match self.room_patterns.get_mut(pattern) {
+            Some(subscribers) => {
+                subscribers.remove(&token);
+                subscribers.is_empty()
+            },
+            None => false
+        };
+
+        if now_empty {
+            self.room_patterns.remove(pattern);
+        }
+    }
+
+    fn unsubscribe_all_patterns(&mut self, token: Token) {
+        self.room_patterns.retain(|_, subscribers| {
+            subscribers.remove(&token);
+            !subscribers.is_empty()
+        });
+    }
+
+    // Runs once the upgrade request is in, before the response goes out.
+    fn select_subprotocol(&mut self, token: Token) {
+        #[cfg(feature = "cluster")]
+        let cluster_protocol = if self.cluster_enabled { Some(cluster::PROTOCOL) } else { None };
+        #[cfg(not(feature = "cluster"))]
+        let cluster_protocol: Option<&str> = None;
+
+        let client = match self.clients.get_mut(&token) {
+            Some(client) if matches!(client.state, ClientState::HandshakeResponse) && client.subprotocol.is_none() => client,
+            _ => return
+        };
+        let offered = match client.header("Sec-WebSocket-Protocol") {
+            Some(offered) => offered,
+            None => return
+        };
+
+        let handlers = &self.subprotocol_handlers;
+        let selected = offered.split(',')
+            .map(|protocol| protocol.trim())
+            .find(|protocol| handlers.contains_key(*protocol) || Some(*protocol) == cluster_protocol);
+        if let Some(protocol) = selected {
+            client.handler = handlers.get(protocol).cloned();
+            client.subprotocol = Some(protocol.to_string());
+        }
+    }
+
+    // Runs once the upgrade request is in, before the response goes out.
+    fn start_session(&mut self, token: Token) {
+        if self.config.session_ttl_ms.is_none() {
+            return;
+        }
+        let requested = match self.clients.get(&token) {
+            Some(client) if matches!(client.state, ClientState::HandshakeResponse) && client.resume_token.is_none() =>
+                client.header(RESUME_TOKEN_HEADER),
+            _ => return
+        };
+
+        // A session still in use by another connection can't be taken over.
+        let resumable = requested.filter(|resume_token| {
+            self.sessions.get(resume_token).is_some_and(|session| session.is_idle())
+        });
+        let resume_token = match resumable {
+            Some(resume_token) => {
+                self.resume_session(token, &resume_token);
+                resume_token
+            },
+            None => {
+                let resume_token = session::new_resume_token();
+                self.sessions.insert(resume_token.clone(), Session::new(token));
+                resume_token
+            }
+        };
+
+        if let Some(client) = self.clients.get_mut(&token) {
+            client.resume_token = Some(resume_token);
+        }
+    }
+
+    fn resume_session(&mut self, token: Token, resume_token: &str) {
+        let (previous, rooms) = {
+            let session = self.sessions.get_mut(resume_token).unwrap();
+            let previous = session.token;
+            session.token = token;
+            session.expires_at = None;
+            (previous, std::mem::take(&mut session.rooms))
+        };
+        println!("{:?} resumed the session of {:?}", token, previous);
+
+        for room in rooms {
+            self.join_room(token, &room);
+        }
+
+        let (replay, rest): (VecDeque<_>, VecDeque<_>) = self.dead_letters.drain(..)
+            .partition(|&(t, _, reason)| t == previous && reason == DeadLetterReason::Disconnected);
+        self.dead_letters = rest;
+        if let Some(client) = self.clients.get_mut(&token) {
+            for (_, frame, _) in replay {
+                client.enqueue_deferred(frame);
+            }
+        }
+    }
+
+    // Keeps the session of a dropped client around for it to resume.
+    fn suspend_session(&mut self, token: Token) {
+        let (resume_token, ttl_ms) = match (self.clients.get(&token), self.config.session_ttl_ms) {
+            (Some(client), Some(ttl_ms)) => match client.resume_token {
+                Some(ref resume_token) => (resume_token.clone(), ttl_ms),
+                None => return
+            },
+            _ => return
+        };
+        let rooms = self.rooms_for_client(token);
+
+        match self.sessions.get_mut(&resume_token) {
+            Some(session) if session.token == token => {
+                session.rooms = rooms;
+                session.expires_at = Some(Instant::now() + Duration::from_millis(ttl_ms));
+            },
+            _ => return
+        }
+        self.timers.timeout_ms(TimerEvent::SessionExpiry(resume_token), ttl_ms);
+    }
+
+    fn expire_session(&mut self, resume_token: String) {
+        // The session may have been resumed, and dropped again, since the timer was set.
+        if self.sessions.get(&resume_token).is_some_and(|session| session.is_expired(Instant::now())) {
+            self.sessions.remove(&resume_token);
+        }
+    }
+
+    fn leave_all_rooms(&mut self, token: Token) {
+        for room in self.rooms_for_client(token) {
+            self.leave_room(token, &room);
+        }
+    }
+
+    pub fn rooms_for_client(&self, token: Token) -> Vec<String> {
+        match self.client_rooms.get(&token) {
+            Some(rooms) => rooms.iter().cloned().collect(),
+            None => Vec::new()
+        }
+    }
+
+    pub fn kick(&mut self, token: Token, reason: &str) -> Result<(), WebSocketError> {
+        let client = match self.clients.get_mut(&token) {
+            Some(client) => client,
+            None => return Err(WebSocketError::ClientNotFound)
+        };
+
+        client.flush_and_close(CloseCode::PolicyViolation, reason);
+        client.reregister(self.poll.registry(), token)?;
+        Ok(())
+    }
+
+    pub fn schedule_once(&mut self, token: Token, frame: WebSocketFrame, delay: Duration) -> ScheduleHandle {
+        self.scheduler.schedule(ScheduleTarget::Client(token), frame, delay, None)
+    }
+
+    pub fn schedule_repeat(&mut self, token: Token, frame: WebSocketFrame, interval: Duration) -> ScheduleHandle {
+        self.scheduler.schedule(ScheduleTarget::Client(token), frame, interval, Some(interval))
+    }
+
+    pub fn schedule_broadcast(&mut self, frame: WebSocketFrame, interval: Duration) -> ScheduleHandle {
+        self.scheduler.schedule(ScheduleTarget::Broadcast, frame, interval, Some(interval))
+    }
+
+    pub fn cancel_schedule(&mut self, handle: ScheduleHandle) -> bool {
+        self.scheduler.cancel(handle)
+    }
+
+    fn run_scheduler(&mut self) {
+        for (target, frame) in self.scheduler.due(Instant::now()) {
+            match target {
+                ScheduleTarget::Client(token) => {
+                    if let Err(e) = self.send_to(token, frame) {
+                        println!("scheduled send to {:?} failed: {}", token, e);
+                    }
+                },
+                ScheduleTarget::Broadcast => self.broadcast(frame)
+            }
+        }
+    }
+
+    pub fn for_each_client<F: FnMut(Token, &WebSocketClient)>(&self, mut f: F) {
+        for (token, client) in self.clients.iter() {
+            f(*token, client);
+        }
+    }
+
+    pub fn for_each_client_mut<F: FnMut(Token, &mut WebSocketClient)>(&mut self, mut f: F) {
+        for (token, client) in self.clients.iter_mut() {
+            f(*token, client);
+        }
+    }
+
+    pub fn list_rooms(&self) -> Vec<String> {
+        self.rooms.iter()
+            .filter(|&(_, members)| !members.is_empty())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn room_members(&self, room: &str) -> Vec<Token> {
+        match self.rooms.get(room) {
+            Some(members) => members.iter().cloned().collect(),
+            None => Vec::new()
+        }
+    }
+
+    pub fn drain_client(&mut self, token: Token) {
+        let drain = match self.clients.get_mut(&token) {
+            Some(client) => {
+                if client.outgoing.is_empty() || !(client.is_connected() || client.is_closing()) {
+                    false
+                } else {
+                    client.draining = true;
+                    client.interest = Interest::WRITABLE;
+                    client.reregister(self.poll.registry(), token).is_ok()
+                }
+            },
+            None => return
+        };
+
+        if !drain {
+            self.disconnect(token);
+        }
+    }
+
+    fn handle_error(&mut self, token: Token, error: WebSocketError) {
+        let close_code = match error {
+            WebSocketError::Io(_) => None,
+            WebSocketError::InvalidOpcode(_) | WebSocketError::InvalidProxyHeader(_) |
+            WebSocketError::InvalidHandshake(_) => Some(CloseCode::ProtocolError),
+            _ => Some(CloseCode::InternalError)
+        };
+
+        if let WebSocketError::Io(ref e) = error {
+            if let Some(client) = self.clients.get_mut(&token) {
+                client.record(ConnectionEventKind::IoError(e.to_string()));
+            }
+        }
+
+        (self.on_error)(token, error);
+
+        let closing = match (close_code, self.clients.get_mut(&token)) {
+            (Some(code), Some(client)) if client.is_connected() => {
+                client.flush_and_close(code, "");
+                client.reregister(self.poll.registry(), token).is_ok()
+            },
+            _ => false
+        };
+
+        if !closing {
+            self.disconnect(token);
+        }
+    }
+
+    // Emergency stop: stops accepting and queues a close frame for every
+    // connected client. Anything they send afterwards is discarded, and
+    // clients still in the handshake are dropped right away. Unlike `drain`,
+    // the event loop keeps running.
+    pub fn close_all_clients(&mut self, code: CloseCode, reason: &str) {
+        self.pause_accepting();
+
+        let mut closing = Vec::new();
+        let mut dropped = Vec::new();
+        self.for_each_client_mut(|token, client| {
+            if client.is_connected() {
+                client.flush_and_close(code, reason);
+                closing.push(token);
+            } else if !client.is_closing() {
+                dropped.push(token);
+            }
+        });
+        println!("closing {} clients: {}", closing.len(), code);
+
+        for token in closing {
+            let registered = match self.clients.get_mut(&token) {
+                Some(client) => client.reregister(self.poll.registry(), token).is_ok(),
+                None => continue
+            };
+            if !registered {
+                dropped.push(token);
+            }
+        }
+        for token in dropped {
+            self.disconnect(token);
+        }
+    }
+
+    // Stops accepting, asks every client to go away and shuts the event loop
+    // down once they have all disconnected (or the drain deadline passes).
+    pub fn drain(&mut self) {
+        if self.shutting_down {
+            return;
+        }
+        println!("draining {} clients", self.clients.len());
+        self.shutting_down = true;
+        self.pause_accepting();
+
+        let tokens: Vec<Token> = self.clients.keys().cloned().collect();
+        for token in tokens {
+            let closing = match self.clients.get_mut(&token) {
+                Some(client) if client.is_connected() => {
+                    client.flush_and_close(CloseCode::GoingAway, "server restarting");
+                    client.reregister(self.poll.registry(), token).is_ok()
+                },
+                _ => false
+            };
+
+            if !closing {
+                self.disconnect(token);
+            }
+        }
+
+        self.timers.timeout_ms(TimerEvent::DrainDeadline, DRAIN_TIMEOUT_MS);
+        self.finish_drain();
+    }
+
+    fn finish_drain(&mut self) {
+        if !self.shutting_down || !self.clients.is_empty() {
+            return;
+        }
+
+        if let Err(e) = File::create(DRAINED_MARKER_PATH).and_then(|mut f| write!(f, "{}", process::id())) {
+            println!("failed to write {}: {}", DRAINED_MARKER_PATH, e);
+        }
+        println!("all clients drained, shutting down");
+        self.running = false;
+    }
+
+    fn handshake_timed_out(&mut self, token: Token) {
+        let timed_out = match self.clients.get_mut(&token) {
+            Some(client) if client.is_awaiting_handshake() => {
+                client.record(ConnectionEventKind::HandshakeFailed("timed out".to_string()));
+                // Best effort: the connection is dropped whether or not this gets through.
+                let _ = client.socket.write(b"HTTP/1.1 408 Request Timeout\r\n\r\n");
+                true
+            },
+            _ => false
+        };
+
+        if timed_out {
+            println!("handshake timed out for {:?}", token);
+            self.disconnect(token);
+        }
+    }
+
+    // Frame statistics summed over every client, including ones that have
+    // already disconnected.
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = self.closed_metrics;
+        self.for_each_client(|_, client| metrics.add(&client.recv_stats, &client.send_stats));
+        metrics
+    }
+
+    // Frames that never reached their client, oldest first. Only the last
+    // `DEAD_LETTER_CAPACITY` are kept until they're drained.
+    pub fn drain_dead_letters(&mut self) -> Vec<(Token, WebSocketFrame, DeadLetterReason)> {
+        self.dead_letters.drain(..).collect()
+    }
+
+    fn add_dead_letter(&mut self, token: Token, frame: WebSocketFrame, reason: DeadLetterReason) {
+        if self.dead_letters.len() == DEAD_LETTER_CAPACITY {
+            self.dead_letters.pop_front();
+        }
+        self.dead_letters.push_back((token, frame, reason));
+    }
+
+    fn collect_expired(&mut self, token: Token) {
+        let expired = match self.clients.get_mut(&token) {
+            Some(client) => std::mem::take(&mut client.expired),
+            None => return
+        };
+
+        for frame in expired {
+            self.add_dead_letter(token, frame, DeadLetterReason::Expired);
+        }
+    }
+
+    pub fn stats_snapshot(&self) -> ServerStats {
+        let metrics = self.metrics();
+        ServerStats {
+            connections_active: self.clients.len(),
+            connections_total: self.connections_total,
+            frames_received: metrics.recv_stats.count,
+            frames_sent: metrics.send_stats.count,
+            bytes_received: metrics.recv_stats.total_bytes,
+            bytes_sent: metrics.send_stats.total_bytes,
+            rooms: self.rooms.len(),
+            sessions: self.sessions.len(),
+            uptime: self.started_at.elapsed()
+        }
+    }
+
+    fn disconnect(&mut self, token: Token) {
+        // A relayed client takes its upstream connection down with it.
+        if let Some(upstream_token) = self.proxies.remove(&token) {
+            self.close_upstream(upstream_token);
+        }
+        self.suspend_session(token);
+        self.leave_all_rooms(token);
+        self.unsubscribe_all_patterns(token);
+        self.collect_expired(token);
+
+        if let Some(mut client) = self.clients.remove(&token) {
+            for queued in client.outgoing.drain(..) {
+                self.add_dead_letter(token, queued.frame, DeadLetterReason::Disconnected);
+            }
+            if client.is_connected() || client.is_closing() {
+                if let Some(ref on_disconnect) = self.on_disconnect {
+                    on_disconnect(token, client.close_code);
+                }
+            }
+            self.close_socket(token, &mut client.socket, client.registered);
+            self.closed_metrics.add(&client.recv_stats, &client.send_stats);
+            let handler = client.handler.as_ref().unwrap_or(&self.handler);
+            handler.on_close(token, &client.recv_stats, &client.send_stats);
+        }
+        self.finish_drain();
+    }
+
+    fn close_socket(&self, token: Token, socket: &mut TcpStream, registered: bool) {
+        // The peer may already have torn the connection down, which is not worth reporting.
+        match socket.shutdown(Shutdown::Both) {
+            Err(ref e) if e.kind() == ErrorKind::NotConnected => {},
+            Err(e) => (self.on_error)(token, WebSocketError::from(e)),
+            Ok(()) => {}
+        }
+
+        if !registered {
+            return;
+        }
+        if let Err(e) = self.poll.registry().deregister(socket) {
+            (self.on_error)(token, WebSocketError::from(e));
+        }
+    }
+
+    pub fn proxy(&mut self, upstream: SocketAddr, token: Token) -> Result<(), WebSocketError> {
+        if !self.clients.contains_key(&token) {
+            return Err(WebSocketError::ClientNotFound);
+        }
+
+        if let Some(ref mut breaker) = self.upstream_breaker {
+            if !breaker.allow(Instant::now()) {
+                return Err(WebSocketError::CircuitOpen);
+            }
+        }
+
+        let upstream_token = Token(self.token_counter);
+        let connection = match self.upstream_proxy {
+            Some((ref proxy_addr, ref credentials)) => {
+                let credentials = credentials.as_ref().map(|(user, password)| (user.as_str(), password.as_str()));
+                proxy::tunnel_through_proxy(proxy_addr, &upstream.ip().to_string(), upstream.port(), credentials)
+                    .map(|socket| Upstream::with_socket(socket, &upstream.to_string(), token, None))
+            },
+            None => Upstream::connect(&upstream, token).map_err(WebSocketError::from)
+        };
+        let registered = connection.and_then(|mut connection| {
+            self.poll.registry().register(&mut connection.socket, upstream_token,
+                                          Interest::READABLE | Interest::WRITABLE)?;
+            Ok(connection)
+        });
+        let connection = match registered {
+            Ok(connection) => connection,
+            Err(e) => {
+                self.record_upstream_result(false);
+                return Err(e);
+            }
+        };
+
+        self.token_counter += 1;
+        self.upstreams.insert(upstream_token, connection);
+        self.proxies.insert(token, upstream_token);
+        Ok(())
+    }
+
+    fn relay_ready(&mut self, token: Token, event: &Event) {
+        let (client_token, upstream_token) = match (self.upstreams.get(&token), self.proxies.get(&token)) {
+            (Some(upstream), _) => (upstream.peer, token),
+            (None, Some(&upstream_token)) => (token, upstream_token),
+            (None, None) => return
+        };
+
+        let relayed = match (self.clients.get_mut(&client_token), self.upstreams.get_mut(&upstream_token)) {
+            (Some(client), Some(upstream)) => Some((client, upstream)),
+            _ => None
+        };
+        let (result, handshaking, opened) = {
+            let (client, upstream) = match relayed {
+                Some(relayed) => relayed,
+                None => {
+                    println!("relay for {:?} lost one of its ends", client_token);
+                    self.close_relay(client_token, upstream_token);
+                    return;
+                }
+            };
+            let registry = self.poll.registry();
+            let handshaking = !upstream.is_open();
+
+            let result = match proxy::relay(&mut client.socket, upstream, token == upstream_token, event) {
+                Ok(true) => {
+                    let (client_interest, upstream_interest) = upstream.interests();
+                    registry.reregister(&mut client.socket, client_token, client_interest)
+                        .and_then(|_| registry.reregister(&mut upstream.socket, upstream_token, upstream_interest))
+                        .map(|_| true)
+                },
+                other => other
+            };
+            (result, handshaking, upstream.is_open())
+        };
+
+        if handshaking {
+            match result {
+                Ok(true) if opened => self.record_upstream_result(true),
+                Ok(true) => {},
+                _ => self.record_upstream_result(false)
+            }
+        }
+
+        match result {
+            Ok(true) => {},
+            Ok(false) => self.close_relay(client_token, upstream_token),
+            Err(e) => {
+                println!("proxy error for {:?}: {}", client_token, e);
+                self.close_relay(client_token, upstream_token);
+            }
+        }
+    }
+
+    fn record_upstream_result(&mut self, success: bool) {
+        if let Some(ref mut breaker) = self.upstream_breaker {
+            if success {
+                breaker.record_success();
+            } else {
+                breaker.record_failure(Instant::now());
+            }
+        }
+    }
+
+    // Opens a connection to every peer server. Room broadcasts published with
+    // `relay` set are forwarded over these, and the peers deliver them to
+    // their own members. Peers are trusted: anything arriving over the cluster
+    // subprotocol is delivered, so only enable this on a private network.
+    #[cfg(feature = "cluster")]
+    pub fn connect_to_cluster(&mut self, peers: Vec<SocketAddr>) {
+        self.cluster_enabled = true;
+
+        for peer in peers {
+            let token = Token(self.token_counter);
+            let connected = TcpStream::connect(peer).and_then(|socket| {
+                let mut link = Upstream::with_socket(socket, &peer.to_string(), token, Some(cluster::PROTOCOL));
+                self.poll.registry().register(&mut link.socket, token, Interest::READABLE | Interest::WRITABLE)?;
+                Ok(link)
+            });
+
+            match connected {
+                Ok(link) => {
+                    self.token_counter += 1;
+                    self.cluster_peers.insert(token, link);
+                },
+                Err(e) => println!("failed to connect to cluster peer {}: {}", peer, e)
+            }
+        }
+    }
+
+    #[cfg(feature = "cluster")]
+    pub fn publish(&mut self, room: &str, frame: WebSocketFrame, relay: bool) {
+        if relay && frame.is_data() {
+            let message = ClusterMessage {
+                room: room.to_string(),
+                opcode: frame.get_opcode(),
+                payload: frame.payload.clone()
+            };
+            match message.encode() {
+                Some(bytes) => {
+                    let id = rand::random();
+                    self.seen_ids.insert(id);
+                    self.relay_to_cluster(WebSocketFrame::binary(bytes).with_message_id(id));
+                },
+                None => println!("room name too long to relay to the cluster: {} bytes", room.len())
+            }
+        }
+        self.broadcast_room(room, frame);
+    }
+
+    #[cfg(feature = "cluster")]
+    fn relay_to_cluster(&mut self, frame: WebSocketFrame) {
+        let mut bytes = Vec::new();
+        // Frames sent as a client have to be masked.
+        if let Err(e) = frame.write_masked(rand::random(), &mut bytes) {
+            println!("failed to encode cluster message: {}", e);
+            return;
+        }
+
+        let registry = self.poll.registry();
+        for (&token, link) in self.cluster_peers.iter_mut() {
+            // The peer reads the upgrade request on its own; anything sent
+            // along with it would be lost.
+            if !link.is_open() {
+                println!("cluster peer {:?} isn't connected yet, dropping message", token);
+                continue;
+            }
+            link.to_upstream.extend_from_slice(&bytes);
+            let result = link.flush().and_then(|_| {
+                let interest = link.interests().1;
+                registry.reregister(&mut link.socket, token, interest)
+            });
+            if let Err(e) = result {
+                println!("failed to relay to cluster peer {:?}: {}", token, e);
+            }
+        }
+    }
+
+    #[cfg(feature = "cluster")]
+    fn receive_cluster_messages(&mut self, token: Token) {
+        let messages = match self.clients.get_mut(&token) {
+            Some(client) => std::mem::take(&mut client.incoming),
+            None => return
+        };
+
+        for message in messages {
+            let mut frame = message.into_frame();
+            let id = match frame.take_message_id() {
+                Some(id) if frame.get_opcode() == OpCode::BinaryFrame => id,
+                _ => {
+                    println!("ignoring cluster message without an id from {:?}", token);
+                    continue;
+                }
+            };
+            // Already delivered, e.g. it reached us over more than one link.
+            if !self.seen_ids.insert(id) {
+                continue;
+            }
+
+            let message = match ClusterMessage::decode(&frame.payload) {
+                Some(message) => message,
+                None => {
+                    println!("ignoring malformed cluster message from {:?}", token);
+                    continue;
+                }
+            };
+            let room = message.room.clone();
+            if let Some(frame) = message.into_frame() {
+                self.broadcast_room(&room, frame);
+            }
+        }
+    }
+
+    // Peers don't send anything over our links besides the upgrade response
+    // and control frames, so incoming data is only read to keep the socket drained.
+    #[cfg(feature = "cluster")]
+    fn cluster_ready(&mut self, token: Token, event: &Event) {
+        let result = {
+            let registry = self.poll.registry();
+            let link = self.cluster_peers.get_mut(&token).unwrap();
+
+            let open = if event.is_readable() { link.read() } else { Ok(true) };
+            link.to_client.clear();
+            open.and_then(|open| {
+                link.flush()?;
+                let interest = link.interests().1;
+                registry.reregister(&mut link.socket, token, interest)?;
+                Ok(open && !event.is_read_closed() && !event.is_error())
+            })
+        };
+
+        let reason = match result {
+            Ok(true) => return,
+            Ok(false) => "connection closed".to_string(),
+            Err(e) => e.to_string()
+        };
+        println!("lost cluster peer {:?}: {}", token, reason);
+        if let Some(mut link) = self.cluster_peers.remove(&token) {
+            self.close_socket(token, &mut link.socket, true);
+        }
+    }
+
+    fn close_relay(&mut self, client_token: Token, upstream_token: Token) {
+        self.close_upstream(upstream_token);
+        self.disconnect(client_token);
+    }
+
+    fn close_upstream(&mut self, upstream_token: Token) {
+        if let Some(mut upstream) = self.upstreams.remove(&upstream_token) {
+            self.close_socket(upstream_token, &mut upstream.socket, true);
+        }
+    }
+
+    pub fn add_filter<F: FrameFilter>(&mut self, filter: F) {
+        self.filters.push(Box::new(filter));
+    }
+}
+
+impl WebSocketServer {
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        self.running = true;
+
+        while self.running {
+            self.poll_once(&mut events, None)?;
+        }
+        Ok(())
+    }
+
+    // Waits for events for no longer than `max_wait` (or the next timer) and
+    // handles them.
+    fn poll_once(&mut self, events: &mut Events, max_wait: Option<Duration>) -> io::Result<()> {
+        let timeout = match (self.timers.next_timeout(Instant::now()), max_wait) {
+            (Some(timeout), Some(max_wait)) => Some(cmp::min(timeout, max_wait)),
+            (timeout, max_wait) => timeout.or(max_wait)
+        };
+        match self.poll.poll(events, timeout) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(e)
+        }
+
+        for event in events.iter() {
+            if event.token() == WAKER_TOKEN {
+                while let Ok(command) = self.commands.try_recv() {
+                    self.notify(command);
+                }
+            } else {
+                self.ready(event);
+            }
+        }
+
+        for timeout in self.timers.expired(Instant::now()) {
+            self.timeout(timeout);
+        }
+        self.tick();
+        Ok(())
+    }
+
+    // Hot restart: stops accepting, keeps serving the connected clients until
+    // they have all gone (for up to `DRAIN_TIMEOUT_MS`), then moves the
+    // listening sockets over to `new_server`, which is returned ready to `run`.
+    // Connections waiting to be accepted meanwhile stay queued in the backlog.
+    pub fn drain_and_restart(mut self, mut new_server: WebSocketServer) -> io::Result<WebSocketServer> {
+        println!("handing off to the new server once {} clients are gone", self.clients.len());
+        self.pause_accepting();
+
+        let deadline = Instant::now() + Duration::from_millis(DRAIN_TIMEOUT_MS);
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        while !self.clients.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                println!("{} clients still connected at the handoff, disconnecting them", self.clients.len());
+                break;
+            }
+            self.poll_once(&mut events, Some(deadline - now))?;
+        }
+        let tokens: Vec<Token> = self.clients.keys().cloned().collect();
+        for token in tokens {
+            self.disconnect(token);
+        }
+
+        let sockets = self.listeners.drain(..).map(|(_, socket)| socket).collect();
+        new_server.add_listeners(sockets)?;
+        Ok(new_server)
+    }
+
+    fn ready(&mut self, event: &Event) {
+        let token = event.token();
+        #[cfg(feature = "cluster")]
+        if self.cluster_peers.contains_key(&token) {
+            self.cluster_ready(token, event);
+            return;
+        }
+        if self.upstreams.contains_key(&token) || self.proxies.contains_key(&token) {
+            self.relay_ready(token, event);
+            return;
+        }
+
+        if event.is_readable() {
+            match token {
+                // Readiness is edge-triggered, so accept until the queue is empty.
+                token if self.is_listener(token) => loop {
+                    let accepted = match self.listeners.iter().find(|&&(listener_token, _)| listener_token == token) {
+                        Some((_, socket)) => socket.accept(),
+                        None => return
+                    };
+                    let (mut client_socket, peer_addr) = match accepted {
+                        Ok((sock, addr)) => (sock, addr),
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            println!("Accept error: {}", e);
+                            return;
+                        }
+                    };
+                    if let Some(reason) = self.refuse_connection(peer_addr.ip()) {
+                        println!("refusing connection from {}: {}", peer_addr, reason);
+                        continue;
+                    }
+                    if self.config.nodelay {
+                        if let Err(e) = client_socket.set_nodelay(true) {
+                            println!("failed to set TCP_NODELAY for {}: {}", peer_addr, e);
+                        }
+                    }
+
+                    let new_token = Token(self.token_counter);
+                    if let Err(e) = self.poll.registry().register(&mut client_socket, new_token, Interest::READABLE) {
+                        // Dropping the socket closes the connection.
+                        println!("failed to register connection from {}: {}", peer_addr, e);
+                        continue;
+                    }
+
+                    let mut client = WebSocketClient::new(client_socket, peer_addr);
+                    client.awaiting_proxy_header = self.config.proxy_protocol;
+                    client.rate_limiter = self.config.rate_limit.map(|(rate, burst)| TokenBucket::new(rate, burst));
+                    client.read_buf = CircularReadBuffer::new(self.config.max_payload_len);
+                    client.buffer_pool = self.buffer_pool.clone();
+                    client.config = self.config.clone();
+                    client.handshake_retries_remaining = self.config.max_handshake_retries;
+                    #[cfg(feature = "sequence")]
+                    if self.config.sequence_numbers {
+                        client.extensions.sequence = Some(SequenceExtension::new());
+                        client.send_sequence = Some(SequenceExtension::new());
+                    }
+                    if let Some(ref on_ping) = self.on_ping {
+                        let on_ping = on_ping.clone();
+                        client.on_ping = Some(Box::new(move |payload: &[u8]| on_ping(new_token, payload)));
+                    }
+                    client.handler = self.handler_factory.as_ref().map(|factory| Arc::from(factory()));
+                    self.clients.insert(new_token, client);
+                    self.token_counter += 1;
+                    self.connections_total += 1;
+
+                    self.timers.timeout_ms(TimerEvent::HandshakeTimeout(new_token), self.config.handshake_timeout_ms);
+                },
+                token => {
+                    // The client may have been dropped by an earlier event in this batch.
+                    let client = match self.clients.get_mut(&token) {
+                        Some(client) => client,
+                        None => return
+                    };
+                    let result = client.read(&self.filters);
+                    client.consecutive_errors = match result {
+                        Ok(()) => 0,
+                        Err(_) => client.consecutive_errors.saturating_add(1)
+                    };
+
+                    let registered = match client.error_backoff_ms() {
+                        Some(delay) => {
+                            self.timers.timeout_ms(TimerEvent::Reregister(token), delay);
+                            Ok(())
+                        },
+                        None => client.reregister(self.poll.registry(), token)
+                    };
+                    let result = result.and(registered.map_err(WebSocketError::from));
+
+                    match result {
+                        Ok(()) => {
+                            self.dispatch_messages(token);
+                            self.respond_http(token);
+                            self.select_subprotocol(token);
+                            self.start_session(token);
+                        },
+                        Err(e) => {
+                            if let Some(status) = self.clients.get(&token).and_then(|client| client.rejected_with) {
+                                self.log_access(token, None, status, 0);
+                            }
+                            self.handle_error(token, e)
+                        }
+                    }
+                }
+            }
+        }
+
+        if event.is_writable() {
+            let (result, connected) = match self.clients.get_mut(&token) {
+                Some(client) => {
+                    let registry = self.poll.registry();
+                    let handshaking = matches!(client.state, ClientState::HandshakeResponse);
+                    let result = client.write(&self.filters).and_then(|()| {
+                        if client.draining && client.outgoing.is_empty() && client.write_buf.is_empty() {
+                            Ok(true)
+                        } else {
+                            client.reregister(registry, token).map(|()| false).map_err(WebSocketError::from)
+                        }
+                    });
+                    (result, handshaking && client.is_connected())
+                },
+                None => return
+            };
+
+            if connected {
+                self.log_access(token, None, 101, 0);
+                if let Some(ref on_connect) = self.on_connect {
+                    on_connect(token, &self.clients[&token]);
+                }
+            }
+            self.collect_expired(token);
+
+            match result {
+                Ok(true) => self.disconnect(token),
+                Ok(false) => {},
+                Err(e) => self.handle_error(token, e)
+            }
+        }
+
+        if (event.is_read_closed() || event.is_error()) && self.clients.contains_key(&token) {
+            self.drain_client(token);
+        }
+    }
+
+    fn notify(&mut self, command: ServerCommand) {
+        match command {
+            ServerCommand::PauseAccepting => self.pause_accepting(),
+            ServerCommand::ResumeAccepting => self.resume_accepting(),
+            ServerCommand::Deliver(token, messages) => self.deliver(token, messages),
+            ServerCommand::Dispatched(token) => self.finish_dispatch(token),
+            ServerCommand::SendTo(token, frame) => {
+                if let Err(e) = self.send_to(token, frame) {
+                    println!("send to {:?} failed: {}", token, e);
+                }
+            },
+            ServerCommand::Broadcast(frame) => self.broadcast(frame),
+            ServerCommand::BroadcastRoom(room, frame) => self.broadcast_room(&room, frame),
+            ServerCommand::Drain => self.drain(),
+            ServerCommand::Shutdown => self.running = false
+        }
+    }
+
+    fn tick(&mut self) {
+        self.tick_count += 1;
+
+        self.for_each_client_mut(|_, client| {
+            if let Some(ref mut limiter) = client.rate_limiter {
+                limiter.refill();
+            }
+        });
+        self.run_scheduler();
+        self.close_slow_readers();
+        self.check_keepalive();
+    }
+
+    // Which limit, if any, a new connection from `ip` would go over.
+    fn refuse_connection(&self, ip: IpAddr) -> Option<&'static str> {
+        if self.config.max_connections.is_some_and(|max| self.clients.len() >= max) {
+            return Some("too many connections");
+        }
+        if let Some(max) = self.config.max_connections_per_ip {
+            if self.clients.values().filter(|client| client.peer_addr.ip() == ip).count() >= max {
+                return Some("too many connections from this address");
+            }
+        }
+        None
+    }
+
+    // Pings connections that have gone quiet and drops those that stay silent
+    // past the idle timeout or don't answer a ping in time.
+    fn check_keepalive(&mut self) {
+        let config = self.config.clone();
+        if config.idle_timeout_ms.is_none() && config.ping_interval_ms.is_none() {
+            return;
+        }
+
+        let mut silent = Vec::new();
+        let registry = self.poll.registry();
+        for (&token, client) in self.clients.iter_mut() {
+            // Relayed traffic bypasses the client's frame parser, so its
+            // socket is left to the relay.
+            if !client.is_connected() || self.proxies.contains_key(&token) {
+                continue;
+            }
+
+            let quiet = client.last_read_at.elapsed();
+            let pong_overdue = match (&client.pending_ping, config.pong_timeout_ms) {
+                (Some((sent_at, _)), Some(timeout_ms)) => sent_at.elapsed() > Duration::from_millis(timeout_ms),
+                _ => false
+            };
+            if pong_overdue || config.idle_timeout_ms.is_some_and(|timeout_ms| quiet > Duration::from_millis(timeout_ms)) {
+                println!("closing {:?}: nothing received for {:?}", token, quiet);
+                // Best effort, as in `close_slow_readers`.
+                if client.write_buf.is_empty() {
+                    let _ = WebSocketFrame::close(CloseCode::GoingAway, "idle timeout").write(&mut client.socket);
+                }
+                client.close_code.get_or_insert(CloseCode::GoingAway);
+                silent.push(token);
+                continue;
+            }
+
+            let ping_due = config.ping_interval_ms.is_some_and(|interval_ms| quiet >= Duration::from_millis(interval_ms));
+            if ping_due && client.pending_ping.is_none() {
+                let pinged = client.send_ping(&[])
+                    .and_then(|()| client.reregister(registry, token).map_err(WebSocketError::from));
+                if let Err(e) = pinged {
+                    println!("failed to ping {:?}: {}", token, e);
+                }
+            }
+        }
+        for token in silent {
+            self.disconnect(token);
+        }
+    }
+
+    fn close_slow_readers(&mut self) {
+        let read_timeout = match self.config.read_timeout {
+            Some(read_timeout) => read_timeout,
+            None => return
+        };
+
+        let mut slow = Vec::new();
+        self.for_each_client_mut(|token, client| {
+            if client.read_started_at.is_some_and(|started| started.elapsed() > read_timeout) {
+                println!("closing {:?}: partial frame pending for over {:?}", token, read_timeout);
+                // Best effort: the connection is dropped whether or not this
+                // gets through. Skipped if it would land in the middle of a
+                // frame that is still partly unsent.
+                if client.write_buf.is_empty() {
+                    let _ = WebSocketFrame::close(CloseCode::PolicyViolation, "read timeout").write(&mut client.socket);
+                }
+                client.close_code.get_or_insert(CloseCode::PolicyViolation);
+                slow.push(token);
+            }
+        });
+        for token in slow {
+            self.disconnect(token);
+        }
+    }
+
+    fn timeout(&mut self, timeout: TimerEvent) {
+        match timeout {
+            // The scheduler itself runs from `tick`; this timer only makes sure the
+            // loop wakes up at least once per interval when it is otherwise idle.
+            TimerEvent::SchedulerTick => self.timers.timeout_ms(TimerEvent::SchedulerTick, SCHEDULER_INTERVAL_MS),
+            TimerEvent::HandshakeTimeout(token) => self.handshake_timed_out(token),
+            TimerEvent::Reregister(token) => {
+                if let Some(client) = self.clients.get_mut(&token) {
+                    if let Err(e) = client.reregister(self.poll.registry(), token) {
+                        println!("failed to re-register {:?} after backoff: {}", token, e);
+                    }
+                }
+            },
+            TimerEvent::CoalesceFlush(token) => self.flush_coalesced(token),
+            TimerEvent::SessionExpiry(resume_token) => self.expire_session(resume_token),
+            TimerEvent::DrainDeadline => {
+                let tokens: Vec<Token> = self.clients.keys().cloned().collect();
+                println!("drain deadline passed, dropping {} clients", tokens.len());
+                for token in tokens {
+                    println!("dropping {:?} ({})", token, self.clients[&token].state_name());
+                    self.disconnect(token);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+pub fn watch_drain_signal(sender: CommandSender) -> io::Result<()> {
+    let mut signals = Signals::new([SIGUSR1])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if sender.send(ServerCommand::Drain).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_socket::MockSocket;
+    use std::sync::Mutex;
+
+    const UPGRADE_REQUEST: &'static [u8] = b"GET /chat HTTP/1.1\r\n\
+                                             Host: localhost\r\n\
+                                             Upgrade: websocket\r\n\
+                                             Connection: Upgrade\r\n\
+                                             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                                             Sec-WebSocket-Version: 13\r\n\r\n";
+
+    fn client() -> WebSocketClient<MockSocket> {
+        WebSocketClient::new(MockSocket::new(), "127.0.0.1:5000".parse().unwrap())
+    }
+
+    fn connected_client() -> WebSocketClient<MockSocket> {
+        let mut client = client();
+        client.socket.push_read(UPGRADE_REQUEST);
+        client.read(&[]).unwrap();
+        client.write(&[]).unwrap();
+        client.socket.write_data.clear();
+        client
+    }
+
+    fn masked(frame: WebSocketFrame) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        frame.write_masked([0x12, 0x34, 0x56, 0x78], &mut bytes).unwrap();
+        bytes
+    }
+
+    fn sent_frame(client: &WebSocketClient<MockSocket>) -> WebSocketFrame {
+        WebSocketFrame::read(&mut &client.socket.write_data[..]).unwrap()
+    }
+
+    #[test]
+    fn handshake_moves_through_states() {
+        let mut client = client();
+        assert_eq!(client.state_name(), "awaiting_handshake");
+
+        client.socket.push_read(UPGRADE_REQUEST);
+        client.read(&[]).unwrap();
+        assert_eq!(client.state_name(), "handshake_response");
+        assert!(client.interest.is_writable());
+
+        client.write(&[]).unwrap();
+        assert_eq!(client.state_name(), "connected");
+        assert!(client.interest.is_readable());
+
+        let response = String::from_utf8(client.socket.write_data.clone()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn handshake_response_carries_resume_token() {
+        let mut client = client();
+        client.socket.push_read(UPGRADE_REQUEST);
+        client.read(&[]).unwrap();
+        client.resume_token = Some("abc".to_string());
+        client.write(&[]).unwrap();
+
+        let response = String::from_utf8(client.socket.write_data.clone()).unwrap();
+        assert!(response.ends_with("\r\nX-Resume-Token: abc\r\n\r\n"));
+    }
+
+    #[test]
+    fn invalid_deflate_window_bits_are_rejected() {
+        let request = String::from_utf8(UPGRADE_REQUEST.to_vec()).unwrap()
+            .replace("\r\n\r\n", "\r\nSec-WebSocket-Extensions: permessage-deflate; server_max_window_bits=20\r\n\r\n");
+        let mut client = client();
+        Arc::make_mut(&mut client.config).compression.enabled = true;
+        client.socket.push_read(request.as_bytes());
+
+        assert!(client.read(&[]).is_err());
+        assert!(client.socket.write_data.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn deflate_is_not_advertised_without_a_codec() {
+        let request = String::from_utf8(UPGRADE_REQUEST.to_vec()).unwrap()
+            .replace("\r\n\r\n", "\r\nSec-WebSocket-Extensions: permessage-deflate; server_max_window_bits=10\r\n\r\n");
+        let mut client = client();
+        Arc::make_mut(&mut client.config).compression.enabled = true;
+        client.socket.push_read(request.as_bytes());
+        client.read(&[]).unwrap();
+        client.write(&[]).unwrap();
+
+        let response = String::from_utf8(client.socket.write_data.clone()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(!response.contains("Sec-WebSocket-Extensions"));
+    }
+
+    #[test]
+    fn malformed_request_heads_are_skipped_while_retries_remain() {
+        let mut junk = b"\x16\x03\x01 junk\r\n\r\n".to_vec();
+        junk.extend_from_slice(UPGRADE_REQUEST);
+
+        let mut client = client();
+        client.handshake_retries_remaining = 1;
+        client.socket.push_read(&junk);
+        client.read(&[]).unwrap();
+        assert_eq!(client.state_name(), "handshake_response");
+
+        let mut strict = self::client();
+        strict.socket.push_read(&junk);
+        assert!(strict.read(&[]).is_err());
+        assert!(strict.socket.write_data.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn handshake_response_names_the_subprotocol() {
+        let mut client = client();
+        client.socket.push_read(UPGRADE_REQUEST);
+        client.read(&[]).unwrap();
+        client.subprotocol = Some("chat".to_string());
+        client.write(&[]).unwrap();
+
+        let response = String::from_utf8(client.socket.write_data.clone()).unwrap();
+        assert!(response.ends_with("\r\nSec-WebSocket-Protocol: chat\r\n\r\n"));
+    }
+
+    #[test]
+    fn handshake_split_across_reads() {
+        let mut client = client();
+        let (first, rest) = UPGRADE_REQUEST.split_at(20);
+
+        client.socket.push_read(first);
+        client.read(&[]).unwrap();
+        assert_eq!(client.state_name(), "awaiting_handshake");
+
+        client.socket.push_read(rest);
+        client.read(&[]).unwrap();
+        assert_eq!(client.state_name(), "handshake_response");
+    }
+
+    #[test]
+    fn text_frame_round_trip() {
+        let mut client = connected_client();
+        client.socket.push_read(&masked(WebSocketFrame::from("hello")));
+        client.read(&[]).unwrap();
+
+        let incoming = std::mem::take(&mut client.incoming);
+        assert_eq!(incoming, vec![Message::Text("hello".to_string())]);
+
+        for message in incoming {
+            for reply in GreetingHandler.on_message(Token(1), message) {
+                client.enqueue(reply.into_frame());
+            }
+        }
+        assert_eq!(client.outgoing.len(), 1);
+        assert_eq!(client.outgoing[0].frame.payload, b"hi there!");
+
+        client.write(&[]).unwrap();
+        let sent = sent_frame(&client);
+        assert_eq!(sent.get_opcode(), OpCode::TextFrame);
+        assert_eq!(sent.payload, b"hi there!");
+    }
+
+    #[cfg(feature = "sequence")]
+    #[test]
+    fn sequence_numbers_are_only_stamped_when_enabled() {
+        let mut client = connected_client();
+        client.enqueue(WebSocketFrame::from("plain"));
+        client.write(&[]).unwrap();
+        assert!(!sent_frame(&client).rsv2());
+
+        client.socket.write_data.clear();
+        client.send_sequence = Some(SequenceExtension::new());
+        client.enqueue(WebSocketFrame::from("stamped"));
+        client.write(&[]).unwrap();
+        let sent = sent_frame(&client);
+        assert!(sent.rsv2());
+        assert_eq!(sent.payload.len(), "stamped".len() + 8);
+    }
+
+    #[test]
+    fn ping_is_answered_with_pong() {
+        let mut client = connected_client();
+        client.socket.push_read(&masked(WebSocketFrame::ping(b"pp".to_vec())));
+        client.read(&[]).unwrap();
+
+        client.write(&[]).unwrap();
+        let sent = sent_frame(&client);
+        assert_eq!(sent.get_opcode(), OpCode::Pong);
+        assert_eq!(sent.payload, b"pp");
+    }
+
+    #[test]
+    fn compressed_frames_close_with_protocol_error() {
+        let mut client = connected_client();
+        let mut frame = WebSocketFrame::from("compressed");
+        frame.set_rsv1(true);
+        client.socket.push_read(&masked(frame));
+        client.read(&[]).unwrap();
+
+        assert!(client.incoming.is_empty());
+        client.write(&[]).unwrap();
+        assert_eq!(sent_frame(&client).close_code(), Some(CloseCode::ProtocolError));
+    }
+
+    #[test]
+    fn send_ping_rejects_oversized_payloads() {
+        let mut client = connected_client();
+        match client.send_ping(&[0; 126]) {
+            Err(WebSocketError::PayloadTooLarge { limit: 125, actual: 126 }) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert!(client.outgoing.is_empty());
+
+        client.send_ping(b"still there?").unwrap();
+        assert!(client.interest.is_writable());
+        client.write(&[]).unwrap();
+        let sent = sent_frame(&client);
+        assert_eq!(sent.get_opcode(), OpCode::Ping);
+        assert_eq!(sent.payload, b"still there?");
+    }
+
+    #[test]
+    fn ping_handler_sets_the_pong_payload() {
+        let mut client = connected_client();
+        client.on_ping = Some(Box::new(|payload: &[u8]| [payload, b" 1234"].concat()));
+        client.socket.push_read(&masked(WebSocketFrame::ping(b"t".to_vec())));
+        client.read(&[]).unwrap();
+        client.write(&[]).unwrap();
+
+        let pong = sent_frame(&client);
+        assert_eq!(pong.get_opcode(), OpCode::Pong);
+        assert_eq!(pong.payload, b"t 1234");
+    }
+
+    #[test]
+    fn unanswered_ping_closes_the_connection() {
+        let mut client = connected_client();
+        client.send_ping(b"1").unwrap();
+        client.write(&[]).unwrap();
+
+        // A pong with the wrong payload doesn't count as an answer.
+        client.socket.push_read(&masked(WebSocketFrame::unsolicited_pong(b"0".to_vec())));
+        client.read(&[]).unwrap();
+        assert!(client.pending_ping.is_some());
+
+        client.socket.push_read(&masked(WebSocketFrame::unsolicited_pong(b"1".to_vec())));
+        client.read(&[]).unwrap();
+        assert!(client.pending_ping.is_none());
+
+        client.send_ping(b"2").unwrap();
+        client.send_ping(b"3").unwrap();
+        assert_eq!(client.state_name(), "closing");
+        assert_eq!(client.close_code, Some(CloseCode::GoingAway));
+    }
+
+    #[test]
+    fn expired_data_frames_are_not_sent() {
+        let mut client = connected_client();
+        Arc::make_mut(&mut client.config).message_ttl = Some(Duration::from_millis(0));
+        client.enqueue(WebSocketFrame::from("stale"));
+        client.enqueue(WebSocketFrame::close(CloseCode::Normal, ""));
+        thread::sleep(Duration::from_millis(2));
+
+        client.write(&[]).unwrap();
+        let sent = sent_frame(&client);
+        assert!(sent.is_close());
+        assert_eq!(client.expired.len(), 1);
+        assert_eq!(client.expired[0].payload, b"stale");
+    }
+
+    #[test]
+    fn close_is_echoed_and_then_hangs_up() {
+        let mut client = connected_client();
+        client.socket.push_read(&masked(WebSocketFrame::close(CloseCode::Normal, "bye")));
+        client.read(&[]).unwrap();
+
+        client.write(&[]).unwrap();
+        assert_eq!(sent_frame(&client).close_code(), Some(CloseCode::Normal));
+        assert_eq!(client.state_name(), "closing");
+        assert_eq!(client.close_code, Some(CloseCode::Normal));
+        assert!(client.interest.is_readable());
+    }
+
+    #[test]
+    fn partially_written_frames_are_finished_on_the_next_writable_event() {
+        let mut client = connected_client();
+        client.enqueue(WebSocketFrame::from("first"));
+        client.enqueue(WebSocketFrame::from("second"));
+        client.socket.write_capacity = Some(3);
+        client.write(&[]).unwrap();
+        assert_eq!(client.socket.write_data.len(), 3);
+        assert!(client.outgoing.is_empty());
+        assert!(client.interest.is_writable());
+
+        client.socket.write_capacity = None;
+        client.enqueue(WebSocketFrame::from("third"));
+        client.write(&[]).unwrap();
+        assert!(client.write_buf.is_empty());
+        assert!(!client.interest.is_writable());
+
+        let mut input = &client.socket.write_data[..];
+        for expected in ["first", "second", "third"] {
+            assert_eq!(WebSocketFrame::read(&mut input).unwrap().payload, expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn flush_and_close_enters_closing_state() {
+        let mut client = connected_client();
+        client.flush_and_close(CloseCode::GoingAway, "bye");
+        assert_eq!(client.state_name(), "closing");
+
+        // Nothing more is queued once the close frame is out.
+        client.enqueue(WebSocketFrame::from("too late"));
+        assert_eq!(client.outgoing.len(), 1);
+        assert!(client.outgoing[0].frame.is_close());
+    }
+
+    #[test]
+    fn rooms_for_client_follows_joins_and_leaves() {
+        let mut server = WebSocketServer::new();
+        server.join_room(Token(1), "lobby");
+        server.join_room(Token(1), "games");
+        server.join_room(Token(2), "lobby");
+
+        let mut rooms = server.rooms_for_client(Token(1));
+        rooms.sort();
+        assert_eq!(rooms, vec!["games".to_string(), "lobby".to_string()]);
+
+        server.leave_room(Token(1), "games");
+        assert_eq!(server.rooms_for_client(Token(1)), vec!["lobby".to_string()]);
+
+        server.disconnect(Token(1));
+        assert!(server.rooms_for_client(Token(1)).is_empty());
+        assert_eq!(server.room_members("lobby"), vec![Token(2)]);
+        assert!(!server.rooms.contains_key("games"));
+    }
+
+    #[test]
+    fn rename_room_keeps_members() {
+        let mut server = WebSocketServer::new();
+        server.join_room(Token(1), "lobby");
+        server.join_room(Token(2), "games");
+
+        assert!(server.rename_room("missing", "other").is_err());
+        assert!(server.rename_room("lobby", "games").is_err());
+
+        server.rename_room("lobby", "hall").unwrap();
+        assert_eq!(server.room_members("hall"), vec![Token(1)]);
+        assert!(server.room_members("lobby").is_empty());
+        assert_eq!(server.rooms_for_client(Token(1)), vec!["hall".to_string()]);
+    }
+
+    #[test]
+    fn leave_all_rooms_drops_empty_rooms() {
+        let mut server = WebSocketServer::new();
+        server.join_room(Token(1), "lobby");
+        server.join_room(Token(1), "games");
+        server.join_room(Token(2), "games");
+
+        server.leave_all_rooms(Token(1));
+        assert!(server.rooms_for_client(Token(1)).is_empty());
+        assert_eq!(server.list_rooms(), vec!["games".to_string()]);
+        assert_eq!(server.room_members("games"), vec![Token(2)]);
+    }
+
+    #[test]
+    fn dead_letters_keep_only_the_most_recent() {
+        let mut server = WebSocketServer::new();
+        for i in 0..DEAD_LETTER_CAPACITY + 1 {
+            server.add_dead_letter(Token(i), WebSocketFrame::from("lost"), DeadLetterReason::Expired);
+        }
+
+        let dead_letters = server.drain_dead_letters();
+        assert_eq!(dead_letters.len(), DEAD_LETTER_CAPACITY);
+        assert_eq!(dead_letters[0].0, Token(1));
+        assert_eq!(dead_letters[0].2, DeadLetterReason::Expired);
+        assert!(server.drain_dead_letters().is_empty());
+    }
+
+    #[test]
+    fn messages_from_one_client_are_handled_in_order() {
+        struct SlowRecorder(Arc<Mutex<Vec<Message>>>);
+
+        impl ConnectionHandler for SlowRecorder {
+            fn on_message(&self, _token: Token, message: Message) -> Vec<Message> {
+                if message == Message::Text("first".to_string()) {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                self.0.lock().unwrap().push(message);
+                Vec::new()
+            }
+        }
+
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let mut server = WebSocketServer::new().with_handler(SlowRecorder(handled.clone()));
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let token = Token(1);
+        server.clients.insert(token, WebSocketClient::new(TcpStream::connect(addr).unwrap(), addr));
+
+        for text in &["first", "second", "third"] {
+            server.clients.get_mut(&token).unwrap().incoming.push(Message::Text(text.to_string()));
+            server.dispatch_messages(token);
+        }
+        assert_eq!(server.clients[&token].incoming.len(), 2);
+
+        while handled.lock().unwrap().len() < 3 {
+            let command = server.commands.recv_timeout(Duration::from_secs(5)).unwrap();
+            server.notify(command);
+        }
+        let texts = ["first", "second", "third"].iter().map(|text| Message::Text(text.to_string()));
+        assert_eq!(*handled.lock().unwrap(), texts.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn disconnect_tears_down_the_relay() {
+        let mut server = WebSocketServer::new();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let token = Token(1);
+        server.clients.insert(token, WebSocketClient::new(TcpStream::connect(addr).unwrap(), addr));
+        server.proxy(addr, token).unwrap();
+        assert_eq!(server.upstreams.len(), 1);
+
+        server.disconnect(token);
+        assert!(server.proxies.is_empty());
+        assert!(server.upstreams.is_empty());
+    }
+
+    #[test]
+    fn oversized_frames_are_fragmented_when_written() {
+        let mut client = connected_client();
+        client.enqueue(WebSocketFrame::from("hello world"));
+        Arc::make_mut(&mut client.config).max_frame_size = Some(4);
+        client.write(&[]).unwrap();
+
+        let mut written = &client.socket.write_data[..];
+        let mut fragments = Vec::new();
+        while !written.is_empty() {
+            fragments.push(WebSocketFrame::read(&mut written).unwrap());
+        }
+        assert_eq!(fragments.len(), 3);
+        assert_eq!(fragments[0].get_opcode(), OpCode::TextFrame);
+        assert_eq!(fragments[2].get_opcode(), OpCode::Continuation);
+        assert!(!fragments[1].fin());
+        assert!(fragments[2].fin());
+        assert_eq!(fragments[1].payload, b"o wo");
+        assert_eq!(fragments[2].payload, b"rld");
+    }
+
+    #[test]
+    fn send_text_fragmented_queues_each_fragment() {
+        let mut client = connected_client();
+        Arc::make_mut(&mut client.config).message_ttl = Some(Duration::from_millis(0));
+        client.send_text_fragmented("hello world", 5);
+        assert!(client.interest.is_writable());
+        assert_eq!(client.outgoing.len(), 3);
+        assert!(client.outgoing.iter().all(|queued| queued.expires_at.is_none()));
+        assert_eq!(client.outgoing[0].frame.get_opcode(), OpCode::TextFrame);
+        assert_eq!(client.outgoing[2].frame.payload, b"d");
+        assert!(client.outgoing[2].frame.fin());
+    }
+
+    #[test]
+    fn data_frames_past_the_queue_limit_are_dropped() {
+        let mut client = connected_client();
+        Arc::make_mut(&mut client.config).max_outgoing_queue = Some(2);
+        for text in &["one", "two", "three"] {
+            client.enqueue(WebSocketFrame::from(*text));
+        }
+        client.send_ping(b"").unwrap();
+
+        assert_eq!(client.outgoing.len(), 3);
+        assert_eq!(client.outgoing[1].frame.payload, b"two");
+        assert_eq!(client.outgoing[2].frame.get_opcode(), OpCode::Ping);
+    }
+
+    #[test]
+    fn partial_frames_wait_for_the_rest() {
+        let mut client = connected_client();
+        let bytes = masked(WebSocketFrame::from("hello"));
+        client.socket.push_read(&bytes[..4]);
+        client.read(&[]).unwrap();
+        assert!(client.incoming.is_empty());
+        assert!(client.read_started_at.is_some());
+
+        client.socket.push_read(&bytes[4..]);
+        client.read(&[]).unwrap();
+        assert_eq!(client.incoming, vec![Message::Text("hello".to_string())]);
+        assert!(client.read_started_at.is_none());
+    }
+
+    #[test]
+    fn paused_client_is_registered_only_while_it_has_frames_to_send() {
+        let poll = Poll::new().unwrap();
+        let mut client = connected_client();
+        client.paused = true;
+        client.reregister(poll.registry(), Token(1)).unwrap();
+        assert!(!client.registered);
+
+        client.enqueue(WebSocketFrame::from("still sending"));
+        client.reregister(poll.registry(), Token(1)).unwrap();
+        assert!(client.registered);
+    }
+
+    #[test]
+    fn bind_multiple_gives_each_listener_its_own_token() {
+        let mut server = WebSocketServer::new();
+        let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        server.bind_multiple(vec![address, address]).unwrap();
+
+        let tokens: Vec<Token> = server.listeners.iter().map(|&(token, _)| token).collect();
+        assert_eq!(tokens, vec![Token(usize::MAX - 1), Token(usize::MAX - 2)]);
+        assert!(server.is_listener(Token(usize::MAX - 2)));
+        assert!(!server.is_listener(Token(1)));
+    }
+
+    #[test]
+    fn drain_and_restart_hands_the_listeners_over() {
+        let mut server = WebSocketServer::new();
+        server.bind_multiple(vec!["127.0.0.1:0".parse().unwrap()]).unwrap();
+        let address = server.listeners[0].1.local_addr().unwrap();
+
+        let new_server = server.drain_and_restart(WebSocketServer::new()).unwrap();
+        assert!(new_server.accepting);
+        assert_eq!(new_server.listeners.len(), 1);
+        assert_eq!(new_server.listeners[0].0, Token(usize::MAX - 1));
+        assert_eq!(new_server.listeners[0].1.local_addr().unwrap(), address);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn from_systemd_needs_socket_activation() {
+        let err = WebSocketServer::from_systemd().err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn builders_fill_in_the_config() {
+        let server = WebSocketServer::new()
+            .with_max_payload_len(1024)
+            .with_send_deadline(500, 3)
+            .with_ping_interval(30_000, 5_000)
+            .with_max_connections_per_ip(10)
+            .with_nodelay(true);
+        let config = server.config();
+        assert_eq!(config.max_payload_len, 1024);
+        assert_eq!(config.send_deadline, Some(Duration::from_millis(500)));
+        assert_eq!(config.max_dropped_frames, 3);
+        assert_eq!(config.ping_interval_ms, Some(30_000));
+        assert_eq!(config.pong_timeout_ms, Some(5_000));
+        assert_eq!(config.max_connections_per_ip, Some(10));
+        assert_eq!(config.max_connections, None);
+        assert!(config.nodelay);
+        assert_eq!(config.max_message_size, ServerConfig::default().max_message_size);
+    }
+
+    #[test]
+    fn plain_http_request_is_not_upgraded() {
+        let mut client = client();
+        client.socket.push_read(b"GET /poll HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        client.read(&[]).unwrap();
+        assert_eq!(client.state_name(), "http_request");
+        assert!(!client.interest.is_readable());
+    }
+}
@@ -0,0 +1,39 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use mio::Token;
+
+use std::time::Instant;
+
+pub const RESUME_TOKEN_HEADER: &'static str = "X-Resume-Token";
+
+// What a client gets back when it reconnects with its resume token.
+pub struct Session {
+    // The connection currently (or most recently) using the session.
+    pub token: Token,
+    // Rooms the client was in when its connection dropped.
+    pub rooms: Vec<String>,
+    // Set once the connection is gone; `None` while a client is using it.
+    pub expires_at: Option<Instant>
+}
+
+impl Session {
+    pub fn new(token: Token) -> Session {
+        Session {
+            token: token,
+            rooms: Vec::new(),
+            expires_at: None
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.expires_at.is_some()
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+pub fn new_resume_token() -> String {
+    URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
@@ -0,0 +1,84 @@
+use std::cmp;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    pub count: u64,
+    pub total_bytes: u64,
+    pub min_bytes: u64,
+    pub max_bytes: u64
+}
+
+impl FrameStats {
+    pub fn record(&mut self, payload_len: usize) {
+        let len = payload_len as u64;
+        if self.count == 0 {
+            self.min_bytes = len;
+        } else {
+            self.min_bytes = cmp::min(self.min_bytes, len);
+        }
+        self.max_bytes = cmp::max(self.max_bytes, len);
+        self.total_bytes += len;
+        self.count += 1;
+    }
+
+    pub fn avg_bytes(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.total_bytes as f64 / self.count as f64
+    }
+
+    pub fn merge(&mut self, other: &FrameStats) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            *self = *other;
+            return;
+        }
+        self.count += other.count;
+        self.total_bytes += other.total_bytes;
+        self.min_bytes = cmp::min(self.min_bytes, other.min_bytes);
+        self.max_bytes = cmp::max(self.max_bytes, other.max_bytes);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Metrics {
+    pub recv_stats: FrameStats,
+    pub send_stats: FrameStats
+}
+
+impl Metrics {
+    pub fn add(&mut self, recv_stats: &FrameStats, send_stats: &FrameStats) {
+        self.recv_stats.merge(recv_stats);
+        self.send_stats.merge(send_stats);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServerStats {
+    pub connections_active: usize,
+    pub connections_total: u64,
+    pub frames_received: u64,
+    pub frames_sent: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub rooms: usize,
+    pub sessions: usize,
+    pub uptime: Duration
+}
+
+impl ServerStats {
+    pub fn to_json(self) -> String {
+        format!("{{\"connections_active\":{},\"connections_total\":{},\
+                 \"frames_received\":{},\"frames_sent\":{},\
+                 \"bytes_received\":{},\"bytes_sent\":{},\
+                 \"rooms\":{},\"sessions\":{},\"uptime_secs\":{}}}",
+                self.connections_active, self.connections_total,
+                self.frames_received, self.frames_sent,
+                self.bytes_received, self.bytes_sent,
+                self.rooms, self.sessions, self.uptime.as_secs())
+    }
+}
@@ -0,0 +1,74 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+struct PendingTimer<T> {
+    deadline: Instant,
+    seq: u64,
+    event: T
+}
+
+// Ordered so the earliest deadline sits at the top of the (max-)heap; timers
+// with the same deadline fire in the order they were set.
+impl<T> Ord for PendingTimer<T> {
+    fn cmp(&self, other: &PendingTimer<T>) -> Ordering {
+        other.deadline.cmp(&self.deadline).then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl<T> PartialOrd for PendingTimer<T> {
+    fn partial_cmp(&self, other: &PendingTimer<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> PartialEq for PendingTimer<T> {
+    fn eq(&self, other: &PendingTimer<T>) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for PendingTimer<T> {}
+
+// One-shot timers for the event loop. The loop passes `next_timeout` to
+// `Poll::poll` and fires whatever `expired` returns once it wakes up.
+pub struct Timers<T> {
+    pending: BinaryHeap<PendingTimer<T>>,
+    next_seq: u64
+}
+
+impl<T> Default for Timers<T> {
+    fn default() -> Timers<T> {
+        Timers::new()
+    }
+}
+
+impl<T> Timers<T> {
+    pub fn new() -> Timers<T> {
+        Timers {
+            pending: BinaryHeap::new(),
+            next_seq: 0
+        }
+    }
+
+    pub fn timeout_ms(&mut self, event: T, delay_ms: u64) {
+        self.pending.push(PendingTimer {
+            deadline: Instant::now() + Duration::from_millis(delay_ms),
+            seq: self.next_seq,
+            event: event
+        });
+        self.next_seq += 1;
+    }
+
+    pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+        self.pending.peek().map(|timer| timer.deadline.saturating_duration_since(now))
+    }
+
+    pub fn expired(&mut self, now: Instant) -> Vec<T> {
+        let mut fired = Vec::new();
+        while self.pending.peek().is_some_and(|timer| timer.deadline <= now) {
+            fired.push(self.pending.pop().unwrap().event);
+        }
+        fired
+    }
+}
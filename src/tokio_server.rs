@@ -0,0 +1,282 @@
+use crate::config::{DEFAULT_MAX_HEADER_SIZE, DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MAX_PAYLOAD_LEN};
+use crate::error::WebSocketError;
+use crate::frame::{CloseCode, OpCode, WebSocketFrame};
+use crate::handler::{ConnectionHandler, GreetingHandler, Message};
+use crate::handshake;
+use crate::http::{self, ParseResult};
+use crate::http_fallback::HttpFallbackHandler;
+use crate::stats::FrameStats;
+use crate::server::HANDSHAKE_BUF_LEN;
+use mio::Token;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+const OUTGOING_QUEUE_LEN: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Limits {
+    max_payload_len: usize,
+    max_message_size: usize,
+    max_header_size: usize
+}
+
+// Alternative to the mio event loop: every connection runs as its own task,
+// with a reader that dispatches to the handler and a writer fed over a channel.
+pub struct TokioServer {
+    bind_address: SocketAddr,
+    handler: Arc<dyn ConnectionHandler>,
+    limits: Limits
+}
+
+impl Default for TokioServer {
+    fn default() -> TokioServer {
+        TokioServer::new()
+    }
+}
+
+impl TokioServer {
+    pub fn new() -> TokioServer {
+        TokioServer {
+            bind_address: "127.0.0.1:10000".parse().unwrap(),
+            handler: Arc::new(GreetingHandler),
+            limits: Limits {
+                max_payload_len: DEFAULT_MAX_PAYLOAD_LEN,
+                max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+                max_header_size: DEFAULT_MAX_HEADER_SIZE
+            }
+        }
+    }
+
+    pub fn with_bind_address(mut self, bind_address: SocketAddr) -> TokioServer {
+        self.bind_address = bind_address;
+        self
+    }
+
+    pub fn with_handler<H: ConnectionHandler>(mut self, handler: H) -> TokioServer {
+        self.handler = Arc::new(handler);
+        self
+    }
+
+    pub fn with_max_payload_len(mut self, max_payload_len: usize) -> TokioServer {
+        self.limits.max_payload_len = max_payload_len;
+        self
+    }
+
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> TokioServer {
+        self.limits.max_message_size = max_message_size;
+        self
+    }
+
+    pub fn with_max_header_size(mut self, max_header_size: usize) -> TokioServer {
+        self.limits.max_header_size = max_header_size;
+        self
+    }
+
+    // Accepts connections until Ctrl-C. Connections already being served keep
+    // running on the runtime until it is dropped.
+    pub async fn run(self) -> io::Result<()> {
+        let listener = TcpListener::bind(self.bind_address).await?;
+        let mut token_counter = 1;
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (socket, _peer_addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            println!("Accept error: {}", e);
+                            continue;
+                        }
+                    };
+                    let token = Token(token_counter);
+                    token_counter += 1;
+
+                    let handler = self.handler.clone();
+                    let limits = self.limits;
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(socket, token, handler, limits).await {
+                            println!("error on {:?}: {}", token, e);
+                        }
+                    });
+                },
+                _ = tokio::signal::ctrl_c() => {
+                    println!("shutting down");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+async fn serve(mut socket: TcpStream, token: Token, handler: Arc<dyn ConnectionHandler>,
+               limits: Limits) -> Result<(), WebSocketError> {
+    let request = read_request(&mut socket, limits.max_header_size).await?;
+
+    // Not a WebSocket upgrade: answer over plain HTTP and close.
+    if !request.is_upgrade() {
+        println!("{} {} over plain HTTP", request.method, request.path);
+        let response = HttpFallbackHandler::new(handler).respond(&request);
+        socket.write_all(&response).await?;
+        socket.shutdown().await?;
+        return Ok(());
+    }
+
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) => key.clone(),
+        None => return Err(WebSocketError::InvalidHandshake("missing Sec-WebSocket-Key".to_string()))
+    };
+    socket.write_all(handshake::accept_response(&key).as_bytes()).await?;
+
+    let (mut reader, writer) = socket.into_split();
+    let (sender, receiver) = mpsc::channel(OUTGOING_QUEUE_LEN);
+    let write_task = tokio::spawn(write_frames(writer, receiver));
+
+    let mut recv_stats = FrameStats::default();
+    let result = read_frames(&mut reader, token, &*handler, limits, &sender, &mut recv_stats).await;
+
+    // Dropping the last sender lets the writer flush what is queued and stop.
+    drop(sender);
+    let send_stats = match write_task.await {
+        Ok(send_stats) => send_stats?,
+        Err(e) => return Err(WebSocketError::from(io::Error::other(e)))
+    };
+    handler.on_close(token, &recv_stats, &send_stats);
+    result
+}
+
+async fn read_request(socket: &mut TcpStream, max_header_size: usize) -> Result<http::HttpRequest, WebSocketError> {
+    let mut request_buf = Vec::new();
+    let mut buf = [0; HANDSHAKE_BUF_LEN];
+
+    loop {
+        let len = socket.read(&mut buf).await?;
+        if len == 0 {
+            return Err(WebSocketError::from(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+
+        request_buf.extend_from_slice(&buf[..len]);
+        if request_buf.len() > max_header_size {
+            // Best effort: the connection is dropped whether or not this gets through.
+            let _ = socket.write_all(b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n").await;
+            return Err(WebSocketError::InvalidHandshake(
+                format!("request headers exceed {} bytes", max_header_size)));
+        }
+
+        match http::parse(&request_buf) {
+            ParseResult::Incomplete => continue,
+            ParseResult::Complete(request) => return Ok(request),
+            ParseResult::Error(reason) => return Err(WebSocketError::InvalidHandshake(reason))
+        }
+    }
+}
+
+// Reads frames until the peer closes the connection, handing complete
+// messages to the handler and queueing its replies for the writer.
+async fn read_frames<R: AsyncRead + Unpin>(reader: &mut R, token: Token, handler: &dyn ConnectionHandler,
+                                           limits: Limits, sender: &mpsc::Sender<WebSocketFrame>,
+                                           recv_stats: &mut FrameStats) -> Result<(), WebSocketError> {
+    let mut fragments = Vec::new();
+    let mut fragment_opcode = None;
+
+    loop {
+        let frame = match WebSocketFrame::read_limited_async(reader, limits.max_payload_len).await {
+            Ok(frame) => frame,
+            Err(WebSocketError::PayloadTooLarge { limit, actual }) => {
+                println!("rejecting frame of {} bytes (limit {})", actual, limit);
+                let _ = sender.send(WebSocketFrame::close(CloseCode::MessageTooBig, "")).await;
+                return Ok(());
+            },
+            Err(e) => return Err(e)
+        };
+        recv_stats.record(frame.payload.len());
+
+        let message = match frame.get_opcode() {
+            opcode @ OpCode::TextFrame | opcode @ OpCode::BinaryFrame => {
+                fragments.clear();
+                if frame.fin() {
+                    fragment_opcode = None;
+                    Some((opcode, frame.payload))
+                } else {
+                    fragment_opcode = Some(opcode);
+                    fragments = frame.payload;
+                    None
+                }
+            },
+            OpCode::Continuation => {
+                let opcode = match fragment_opcode {
+                    Some(opcode) => opcode,
+                    None => {
+                        let _ = sender.send(WebSocketFrame::close(CloseCode::ProtocolError,
+                                                                  "unexpected continuation frame")).await;
+                        return Ok(());
+                    }
+                };
+                if fragments.len() + frame.payload.len() > limits.max_message_size {
+                    println!("assembled message exceeds {} bytes", limits.max_message_size);
+                    let _ = sender.send(WebSocketFrame::close(CloseCode::MessageTooBig, "")).await;
+                    return Ok(());
+                }
+
+                fragments.extend_from_slice(&frame.payload);
+                if frame.fin() {
+                    fragment_opcode = None;
+                    Some((opcode, std::mem::take(&mut fragments)))
+                } else {
+                    None
+                }
+            },
+            OpCode::Ping => {
+                println!("ping/pong");
+                let _ = sender.send(WebSocketFrame::pong(&frame)).await;
+                None
+            },
+            OpCode::ConnectionClose => {
+                let _ = sender.send(WebSocketFrame::close_from(&frame)).await;
+                return Ok(());
+            },
+            _ => None
+        };
+
+        let message = match message {
+            Some((OpCode::TextFrame, payload)) => match String::from_utf8(payload) {
+                Ok(text) => Message::Text(text),
+                Err(_) => {
+                    let _ = sender.send(WebSocketFrame::close(CloseCode::InvalidPayload, "")).await;
+                    return Ok(());
+                }
+            },
+            Some((_, payload)) => Message::Binary(payload),
+            None => continue
+        };
+
+        for reply in handler.on_message_async(token, message).await {
+            if sender.send(reply.into_frame()).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Writes queued frames until the reader stops; the connection closes once a
+// close frame has been sent.
+async fn write_frames(mut writer: OwnedWriteHalf,
+                      mut receiver: mpsc::Receiver<WebSocketFrame>) -> Result<FrameStats, WebSocketError> {
+    let mut send_stats = FrameStats::default();
+
+    while let Some(frame) = receiver.recv().await {
+        frame.write_async(&mut writer).await?;
+        send_stats.record(frame.payload.len());
+
+        if frame.is_close() {
+            break;
+        }
+    }
+    writer.shutdown().await?;
+    Ok(send_stats)
+}
@@ -0,0 +1,57 @@
+// Glob matching for room subscriptions: `*` matches any run of characters
+// (including `/`) and `?` matches exactly one.
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let topic: Vec<char> = topic.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the topic position it was tried at, so a
+    // failed match can backtrack and let the star swallow one more character.
+    let mut star = None;
+
+    while t < topic.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == topic[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn star_matches_any_suffix() {
+        assert!(matches("sports/*", "sports/football"));
+        assert!(matches("sports/*", "sports/"));
+        assert!(matches("sports/*", "sports/football/scores"));
+        assert!(!matches("sports/*", "news/football"));
+        assert!(matches("*/scores", "sports/football/scores"));
+        assert!(matches("*", ""));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(matches("room-?", "room-1"));
+        assert!(!matches("room-?", "room-12"));
+        assert!(!matches("room-?", "room-"));
+    }
+
+    #[test]
+    fn literal_patterns_match_exactly() {
+        assert!(matches("lobby", "lobby"));
+        assert!(!matches("lobby", "lobby2"));
+    }
+}